@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
 
 use anyhow::{self as ah, Context};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use graphviz_rust as gv;
 use graphviz_rust::dot_structures as gs;
 
+use crate::feature_spec::FeatureSpec;
+use crate::npy::{NpyArray, NpyElement};
+
 #[derive(Debug, Copy, Clone)]
 struct Decision{
     feature_idx: usize,
@@ -31,27 +37,135 @@ impl Decision{
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// What scikit's `export_graphviz` reports about the training samples that reached a node
+#[derive(Debug, Clone)]
+struct NodeStats{
+    samples: usize,
+    /// Per-class sample counts, weighted the way scikit weights them
+    value: Vec<f32>,
+}
+
+impl NodeStats{
+    fn try_parse_samples_attr(s: &str) -> ah::Result<Option<usize>> {
+        let Some(samples_raw) = s.strip_prefix("samples = ") else {
+            return Ok(None)
+        };
+        let samples = samples_raw.parse::<usize>().context(format!("Parsing samples from >>{samples_raw}<<"))?;
+        Ok(Some(samples))
+    }
+    /// Parses the `samples = N` and `value = [...]` lines of a node's label, if it has both
+    fn try_parse_label_attrs(attrs: &[&str]) -> ah::Result<Option<Self>> {
+        let mut samples = None;
+        let mut value = None;
+        for attr in attrs{
+            if let Some(s) = Self::try_parse_samples_attr(attr)?{
+                samples = Some(s);
+            }
+            if let Some(v) = Prediction::try_parse_value_attr(attr)?{
+                value = Some(v);
+            }
+        }
+        Ok(samples.zip(value).map(|(samples, value)| Self{samples, value}))
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Prediction{
     class: usize,
+    /// Normalized class distribution of the training samples that reached this leaf,
+    /// i.e. what scikit's `predict_proba` averages over all trees
+    probabilities: Vec<f32>,
+    stats: Option<NodeStats>,
 }
 
 impl Prediction{
-    fn try_parse_label_attr(s: &str) -> ah::Result<Option<Self>> {
-        let Some(class_raw) = s.strip_prefix("class = ") else {
+    /// The text after `class = `, which scikit fills with the class name if it was given one
+    fn try_parse_class_attr(s: &str) -> Option<&str> {
+        s.strip_prefix("class = ")
+    }
+    fn try_parse_value_attr(s: &str) -> ah::Result<Option<Vec<f32>>> {
+        let Some(value_raw) = s.strip_prefix("value = ") else {
+            return Ok(None)
+        };
+        let Some(items_raw) = value_raw.strip_prefix("[").and_then(|v| v.strip_suffix("]")) else {
+            ah::bail!("Expected value to be a list: >>{value_raw}<<");
+        };
+        let values = items_raw.split(",")
+            .map(|item| item.trim().parse::<f32>().context(format!("Parsing class weight from >>{value_raw}<<")))
+            .collect::<ah::Result<Vec<_>>>()?;
+        Ok(Some(values))
+    }
+    /// Parses the `class = ...` and `value = [...]` lines of a node's label into the class index
+    /// and, if there is a `value` to take the index from, the name that the `class` line gives it.
+    ///
+    /// scikit writes `class_names[argmax(value)]` as the class, so with a `value` the class line
+    /// can say anything, like `membrane`. Without one, it has to be the class index itself
+    fn try_parse_class_label(attrs: &[&str]) -> ah::Result<Option<(usize, Option<String>)>> {
+        let mut class_raw = None;
+        let mut value = None;
+        for attr in attrs{
+            if let Some(c) = Self::try_parse_class_attr(attr){
+                class_raw = Some(c);
+            }
+            if let Some(v) = Self::try_parse_value_attr(attr)?{
+                value = Some(v);
+            }
+        }
+        let Some(class_raw) = class_raw else {
+            return Ok(None)
+        };
+        match value {
+            Some(weights) => Ok(Some((argmax(&weights), Some(class_raw.to_owned())))),
+            None => {
+                let class = class_raw.parse::<usize>()
+                    .context(format!("Class >>{class_raw}<< is not an index and there is no value = [...] to infer one from"))?;
+                Ok(Some((class, None)))
+            },
+        }
+    }
+    /// Parses the `class = ...` and `value = [...]` lines of a leaf's label
+    fn try_parse_label_attrs(attrs: &[&str]) -> ah::Result<Option<Self>> {
+        let Some((class, _)) = Self::try_parse_class_label(attrs)? else {
             return Ok(None)
         };
-        let class = class_raw.parse::<usize>().context(format!("Parsing class from {s} >>{class_raw}<<"))?;
-        Ok(Some(Self{class}))
+        let value = attrs.iter().find_map(|attr| Self::try_parse_value_attr(attr).transpose()).transpose()?;
+        let probabilities = match value {
+            None => { // no distribution to go by, so this leaf is a certain vote for its class
+                let mut one_hot = vec![0.0; class + 1];
+                one_hot[class] = 1.0;
+                one_hot
+            },
+            Some(weights) => {
+                let total: f32 = weights.iter().sum();
+                if !total.is_finite() || total <= 0.0 {
+                    ah::bail!("Bad class distribution {weights:?}");
+                }
+                weights.iter().map(|w| w / total).collect()
+            }
+        };
+        Ok(Some(Self{class, probabilities, stats: None}))
     }
 }
 
+/// How each tree's leaf contributes to the `class_<k>_score` variables in the generated shader
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VotingMode{
+    /// Each tree casts a single vote for the class of the leaf it ends up in
+    Hard,
+    /// Each tree contributes the class distribution of its leaf. This matches scikit's
+    /// `RandomForestClassifier.predict_proba` (and therefore `predict`)
+    Soft,
+}
+
 ///////////////////////
 
 #[derive(Debug, Copy, Clone)]
 struct Edge{
     origin: u32,
     target: u32,
+    /// The `headlabel="True"/"False"` attribute, which scikit only emits for the root's edges.
+    /// `True` marks the edge leading to the `<=` branch
+    is_le_branch: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -60,6 +174,7 @@ enum TreeNode{
         decision: Decision,
         le_child: Box<TreeNode>,
         gt_child: Box<TreeNode>,
+        stats: Option<NodeStats>,
     },
     Prediction(Prediction),
 }
@@ -72,9 +187,21 @@ fn write_indent(writer: &mut impl std::fmt::Write, level: usize) -> Result<(), s
 }
 
 impl TreeNode{
+    /// Walks down the tree the same way the generated shader does, returning the leaf `features` ends up in
+    fn leaf_for(&self, features: &[f32]) -> &Prediction{
+        let mut node = self;
+        loop {
+            match node{
+                Self::Prediction(pred) => return pred,
+                Self::Decision { decision, le_child, gt_child, .. } => {
+                    node = if features[decision.feature_idx] <= decision.threshold { le_child } else { gt_child };
+                }
+            }
+        }
+    }
     pub fn highest_class_idx(&self) -> usize{
         match self{
-            Self::Prediction(pred) => pred.class,
+            Self::Prediction(pred) => pred.class.max(pred.probabilities.len() - 1),
             Self::Decision { le_child, gt_child, .. } => {
                 le_child.highest_class_idx().max(gt_child.highest_class_idx())
             }
@@ -83,7 +210,7 @@ impl TreeNode{
     pub fn highest_feature_idx(&self) -> Option<usize>{
         match self{
             Self::Prediction(_) => None,
-            Self::Decision { le_child, gt_child, decision } => {
+            Self::Decision { le_child, gt_child, decision, .. } => {
                 Some(
                     decision.feature_idx
                         .max(le_child.highest_feature_idx().unwrap_or(0))
@@ -92,67 +219,457 @@ impl TreeNode{
             }
         }
     }
-    fn write_wgsl(&self, code: &mut impl std::fmt::Write, indent_level: usize) -> Result<(), std::fmt::Error>{
+    fn collect_used_features(&self, used_features: &mut BTreeSet<usize>){
+        if let Self::Decision { decision, le_child, gt_child, .. } = self{
+            used_features.insert(decision.feature_idx);
+            le_child.collect_used_features(used_features);
+            gt_child.collect_used_features(used_features);
+        }
+    }
+    /// Appends this subtree to `flat` in pre-order, returning the index of this node
+    fn flatten_into(&self, flat: &mut FlatForest) -> u32{
+        let node_idx = flat.nodes.len() as u32;
         match self{
             Self::Prediction(pred) => {
-                write_indent(code, indent_level)?;
-                write!(code, "class_{}_score += 1;\n", pred.class)
+                let leaf_offset = flat.leaf_probabilities.len() as u32;
+                let mut probabilities = pred.probabilities.clone();
+                probabilities.resize(flat.num_classes, 0.0);
+                flat.leaf_probabilities.extend(probabilities);
+                flat.nodes.push(FlatNode{
+                    feature_idx: FlatNode::LEAF, threshold: 0.0, le_child: leaf_offset, gt_child: pred.class as u32
+                });
+            },
+            Self::Decision { decision, le_child, gt_child, .. } => {
+                flat.nodes.push(FlatNode{
+                    feature_idx: decision.feature_idx as u32, threshold: decision.threshold, le_child: 0, gt_child: 0
+                });
+                let le_child_idx = le_child.flatten_into(flat);
+                let gt_child_idx = gt_child.flatten_into(flat);
+                let node = &mut flat.nodes[node_idx as usize];
+                node.le_child = le_child_idx;
+                node.gt_child = gt_child_idx;
+            },
+        }
+        node_idx
+    }
+    fn write_wgsl(
+        &self, code: &mut impl std::fmt::Write, indent_level: usize, voting: VotingMode, feature_exprs: &[String]
+    ) -> Result<(), std::fmt::Error>{
+        match self{
+            Self::Prediction(pred) => match voting {
+                VotingMode::Hard => {
+                    write_indent(code, indent_level)?;
+                    writeln!(code, "class_{}_score += 1.0;", pred.class)
+                },
+                VotingMode::Soft => {
+                    for (class_idx, probability) in pred.probabilities.iter().enumerate(){
+                        if *probability == 0.0 {
+                            continue
+                        }
+                        write_indent(code, indent_level)?;
+                        writeln!(code, "class_{class_idx}_score += {probability:?};")?;
+                    }
+                    Ok(())
+                },
             },
-            Self::Decision { decision, le_child, gt_child } => {
+            Self::Decision { decision, le_child, gt_child, .. } => {
                 let Decision { feature_idx, threshold } = decision;
 
                 write_indent(code, indent_level)?;
-                let feature_var_idx = feature_idx / 3; //FIXME: assuming image is RGB
-                let feature_component_idx = feature_idx % 3; //FIXME: assuming image is RGB
-                write!(code, "if feature_{feature_var_idx}[{feature_component_idx}] <= {threshold} {{\n")?;
-                    le_child.write_wgsl(code, indent_level + 1)?;
+                let feature = &feature_exprs[*feature_idx];
+                writeln!(code, "if {feature} <= {threshold} {{")?;
+                    le_child.write_wgsl(code, indent_level + 1, voting, feature_exprs)?;
                 write_indent(code, indent_level)?;
-                write!(code, "}} else {{\n")?;
-                    gt_child.write_wgsl(code, indent_level + 1)?;
+                writeln!(code, "}} else {{")?;
+                    gt_child.write_wgsl(code, indent_level + 1, voting, feature_exprs)?;
                 write_indent(code, indent_level)?;
-                write!(code, "}}\n")?;
+                writeln!(code, "}}")?;
                 Ok(())
             }
         }
     }
+    /// The per-class counts shown in this node's DOT label. Nodes that don't know their training
+    /// samples fall back to the leaf distributions, summed up over the subtree
+    fn dot_value(&self) -> Vec<f32>{
+        match self{
+            Self::Prediction(Prediction{stats: Some(stats), ..}) | Self::Decision{stats: Some(stats), ..} => stats.value.clone(),
+            Self::Prediction(pred) => pred.probabilities.clone(),
+            Self::Decision{le_child, gt_child, stats: None, ..} => {
+                let (le_value, gt_value) = (le_child.dot_value(), gt_child.dot_value());
+                let mut value = vec![0.0; le_value.len().max(gt_value.len())];
+                for child_value in [le_value, gt_value]{
+                    value.iter_mut().zip(child_value).for_each(|(sum, count)| *sum += count);
+                }
+                value
+            },
+        }
+    }
+    /// Writes this subtree in pre-order the way scikit's `export_graphviz(node_ids=True)` does,
+    /// numbering nodes from `next_id`. `parent` is the id of the parent node and whether this is its `<=` child
+    fn write_dot(
+        &self,
+        out: &mut impl std::fmt::Write,
+        next_id: &mut u32,
+        parent: Option<(u32, bool)>,
+        class_name: &dyn Fn(usize) -> String,
+    ) -> Result<(), std::fmt::Error>{
+        let node_id = *next_id;
+        *next_id += 1;
+
+        let value = self.dot_value();
+        let mut label = format!("node #{node_id}\\n");
+        let (class, stats) = match self{
+            Self::Prediction(pred) => (pred.class, &pred.stats),
+            Self::Decision{decision, stats, ..} => {
+                label += &format!("x[{}] <= {}\\n", decision.feature_idx, decision.threshold);
+                (argmax(&value), stats)
+            },
+        };
+        if let Some(stats) = stats{
+            label += &format!("samples = {}\\n", stats.samples);
+        }
+        let value_items: Vec<String> = value.iter().map(|count| count.to_string()).collect();
+        let escaped_class_name = class_name(class).replace('\\', "\\\\").replace('"', "\\\"");
+        label += &format!("value = [{}]\\nclass = {escaped_class_name}", value_items.join(", "));
+        writeln!(out, "{node_id} [label=\"{label}\"] ;")?;
+
+        match parent{
+            // like scikit, only the edges out of the root say which branch they are
+            Some((0, is_le_branch)) => {
+                let (angle, headlabel) = if is_le_branch { (45, "True") } else { (-45, "False") };
+                writeln!(out, "0 -> {node_id} [labeldistance=2.5, labelangle={angle}, headlabel=\"{headlabel}\"] ;")?;
+            },
+            Some((parent_id, _)) => writeln!(out, "{parent_id} -> {node_id} ;")?,
+            None => (),
+        }
+
+        if let Self::Decision{le_child, gt_child, ..} = self{
+            le_child.write_dot(out, next_id, Some((node_id, true)), class_name)?;
+            gt_child.write_dot(out, next_id, Some((node_id, false)), class_name)?;
+        }
+        Ok(())
+    }
 }
 
 
-fn parse_vert(vert: &gs::Vertex) -> ah::Result<u32>{
+fn parse_node_id(id: &gs::Id) -> Result<u32, TreeError>{
+    id_text(id).parse::<u32>().map_err(|_| TreeError::Syntax(format!("Node id {id} is not a number")))
+}
+
+fn parse_vert(vert: &gs::Vertex) -> Result<u32, TreeError>{
     let gs::Vertex::N(node) = vert else{
-        return Err(ah::anyhow!("Vert is not a node: {vert:?}"))
-    }; 
-    let node_id: &gs::Id = &node.0;
-    node_id.to_string().parse::<u32>().context("Parsing vertex id")
+        return Err(TreeError::Syntax(format!("Vert is not a node: {vert:?}")))
+    };
+    parse_node_id(&node.0)
+}
+
+/// The contents of an id, without the quotes around escaped strings
+fn id_text(id: &gs::Id) -> &str{
+    match id{
+        gs::Id::Html(s) | gs::Id::Escaped(s) | gs::Id::Plain(s) | gs::Id::Anonymous(s) => {
+            s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+        },
+    }
+}
+
+/// Splits a label at its `\\n`s and unescapes the `\\\\` and `\\"` in each line, keeping any other
+/// escape sequence as it is
+fn label_lines(label: &str) -> Vec<String>{
+    let mut lines = vec![String::new()];
+    let mut chars = label.chars();
+    while let Some(c) = chars.next(){
+        let line = lines.last_mut().unwrap();
+        if c != '\\'{
+            line.push(c);
+            continue;
+        }
+        match chars.next(){
+            Some('n') => lines.push(String::new()),
+            Some(escaped @ ('\\' | '"')) => line.push(escaped),
+            Some(other) => line.extend(['\\', other]),
+            None => line.push('\\'),
+        }
+    }
+    lines
 }
 
-fn parse_edge(edge: &gs::Edge) -> ah::Result<Edge>{
+fn parse_edge(edge: &gs::Edge) -> Result<Edge, TreeError>{
     let gs::EdgeTy::Pair(v1, v2) = &edge.ty else {
-        return Err(ah::anyhow!("Don't know how to handle non-pair edges"))
+        return Err(TreeError::Syntax("Don't know how to handle non-pair edges".to_owned()))
     };
-    Ok(Edge{
-        origin: parse_vert(v1)?,
-        target: parse_vert(v2)?,
-    })
+    let origin = parse_vert(v1)?;
+    let target = parse_vert(v2)?;
+    let is_le_branch = match edge.attributes.iter().find(|attr| attr.0.to_string() == "headlabel") {
+        None => None,
+        Some(attr) => match id_text(&attr.1) {
+            "True" => Some(true),
+            "False" => Some(false),
+            other => return Err(TreeError::Syntax(format!("Unexpected headlabel '{other}' on edge {origin} -> {target}"))),
+        },
+    };
+    Ok(Edge{origin, target, is_le_branch})
+}
+
+/// Figures out which of the two outgoing edges of `node_id` is the `<=` branch, returning
+/// the targets as `(le_target, gt_target)`.
+///
+/// The `headlabel` attributes take precedence. Without them we fall back to the node id layout
+/// that scikit's `export_graphviz` emits: nodes are numbered in pre-order, so the `<=` child of
+/// node `n` is always node `n + 1`.
+fn split_out_edges(node_id: u32, out_edges: [Edge; 2]) -> Result<(u32, u32), TreeError>{
+    let [a, b] = out_edges;
+    let ambiguous = TreeError::AmbiguousBranches{node_id, targets: [a.target, b.target]};
+    match (a.is_le_branch, b.is_le_branch) {
+        (Some(true), Some(false)) | (Some(true), None) | (None, Some(false)) => return Ok((a.target, b.target)),
+        (Some(false), Some(true)) | (Some(false), None) | (None, Some(true)) => return Ok((b.target, a.target)),
+        (Some(_), Some(_)) => return Err(ambiguous),
+        (None, None) => (),
+    }
+    let le_child_id = node_id.checked_add(1);
+    match (Some(a.target) == le_child_id, Some(b.target) == le_child_id) {
+        (true, false) => Ok((a.target, b.target)),
+        (false, true) => Ok((b.target, a.target)),
+        _ => Err(ambiguous),
+    }
+}
+
+/// Why a DOT graph couldn't be turned into a [`DecisionTree`]
+#[derive(Debug)]
+pub enum TreeError{
+    /// The DOT itself is malformed, or uses constructs that scikit trees don't
+    Syntax(String),
+    /// The label of a node describes neither a split nor a leaf
+    BadLabel{node_id: u32, message: String},
+    DuplicateNode(u32),
+    /// An edge from or to a node that is never declared
+    DanglingEdge{origin: u32, target: u32},
+    /// There is no node 0 to start the tree from
+    MissingRoot,
+    UnreachableNode(u32),
+    /// Following the edges from this node leads back to it
+    Cycle(u32),
+    /// A node that more than one edge points to
+    MultipleParents(u32),
+    /// Splits need exactly a `<=` and a `>` child
+    WrongChildCount{node_id: u32, num_children: usize},
+    /// Neither the edge labels nor the node ids tell which child of the split is the `<=` branch
+    AmbiguousBranches{node_id: u32, targets: [u32; 2]},
+    /// A leaf's class distribution has a different length than the others in the same tree
+    ClassCountMismatch{node_id: u32, expected: usize, found: usize},
+    /// Two nodes give the same class different names
+    ClassNameConflict{class_idx: usize, names: [String; 2]},
+}
+
+impl std::fmt::Display for TreeError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Self::Syntax(message) => write!(f, "{message}"),
+            Self::BadLabel{node_id, message} => write!(f, "Bad label on node {node_id}: {message}"),
+            Self::DuplicateNode(node_id) => write!(f, "Node {node_id} is declared more than once"),
+            Self::DanglingEdge{origin, target} => write!(f, "Edge {origin} -> {target} leads to or from an undeclared node"),
+            Self::MissingRoot => write!(f, "There is no root node with id 0"),
+            Self::UnreachableNode(node_id) => write!(f, "Node {node_id} can't be reached from the root"),
+            Self::Cycle(node_id) => write!(f, "Node {node_id} is part of a cycle"),
+            Self::MultipleParents(node_id) => write!(f, "Node {node_id} has more than one parent"),
+            Self::WrongChildCount{node_id, num_children} => write!(
+                f, "Split node {node_id} has {num_children} children instead of 2"
+            ),
+            Self::AmbiguousBranches{node_id, targets: [a, b]} => write!(
+                f,
+                "Can't tell which edge out of node {node_id} (to {a} or {b}) is the '<=' branch: \
+                edges are not labeled True/False and the node ids are not in scikit's pre-order layout",
+            ),
+            Self::ClassCountMismatch{node_id, expected, found} => write!(
+                f, "Leaf {node_id} has a distribution over {found} classes, but other leaves have {expected}"
+            ),
+            Self::ClassNameConflict{class_idx, names: [a, b]} => write!(
+                f, "Class {class_idx} is called both '{a}' and '{b}'"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TreeError{}
+
+/// Why a set of trees couldn't be turned into a [`RandomForest`]
+#[derive(Debug)]
+pub enum ForestError{
+    Io{path: PathBuf, source: std::io::Error},
+    /// A `tree_*` file whose name doesn't end in `_`-separated numbers, so it can't be ordered
+    BadFileName(PathBuf),
+    /// Two files whose names have the same numbers, like `tree_3.txt` and `tree_03.txt`
+    DuplicateTreeIndex(PathBuf, PathBuf),
+    Tree{path: PathBuf, source: TreeError},
+    NoTrees,
+    /// Trees disagree on how many classes there are. `tree_idx` is the position in load order
+    ClassCountMismatch{tree_idx: usize, expected: usize, found: usize},
+    /// A tree splits on a feature that the [`FeatureSpec`] doesn't describe
+    UnknownFeature{feature_idx: usize, num_features: usize},
+    /// Trees give the same class different names
+    ClassNameConflict{tree_idx: usize, class_idx: usize, names: [String; 2]},
+    /// A class table that doesn't have exactly one entry per class, in class index order
+    BadClassTable(String),
+}
+
+impl std::fmt::Display for ForestError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Self::Io{path, source} => write!(f, "Reading {}: {source}", path.display()),
+            Self::BadFileName(path) => write!(f, "Can't get a tree index from the name of {}", path.display()),
+            Self::DuplicateTreeIndex(a, b) => write!(f, "{} and {} have the same tree index", a.display(), b.display()),
+            Self::Tree{path, source} => write!(f, "Parsing {}: {source}", path.display()),
+            Self::NoTrees => write!(f, "A forest needs at least one tree"),
+            Self::ClassCountMismatch{tree_idx, expected, found} => write!(
+                f, "Tree #{tree_idx} has {found} classes, but the trees before it have {expected}"
+            ),
+            Self::UnknownFeature{feature_idx, num_features} => write!(
+                f, "Forest splits on feature {feature_idx}, but the feature spec only describes {num_features} features"
+            ),
+            Self::ClassNameConflict{tree_idx, class_idx, names: [a, b]} => write!(
+                f, "Tree #{tree_idx} calls class {class_idx} '{b}', but the trees before it call it '{a}'"
+            ),
+            Self::BadClassTable(message) => write!(f, "Bad class table: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ForestError{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            Self::Io{source, ..} => Some(source),
+            Self::Tree{source, ..} => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Index of the highest score, picking the lowest index on ties like numpy's `argmax`
+pub fn argmax(scores: &[f32]) -> usize{
+    let mut best_idx = 0;
+    for (idx, score) in scores.iter().enumerate(){
+        if *score > scores[best_idx]{
+            best_idx = idx;
+        }
+    }
+    best_idx
+}
+
+/// Writes WGSL that declares `predicted_class: u32` as the [`argmax`] of the `class_<k>_score`
+/// variables, so ties go to the lowest class index just like on the CPU
+pub fn write_wgsl_argmax(out: &mut impl std::fmt::Write, num_classes: usize) -> Result<(), std::fmt::Error> {
+    write!(out, "
+                var predicted_class = 0u;
+                var predicted_class_score = class_0_score;")?;
+    for class_idx in 1..num_classes{
+        write!(out, "
+                if class_{class_idx}_score > predicted_class_score {{
+                    predicted_class = {class_idx}u;
+                    predicted_class_score = class_{class_idx}_score;
+                }}")?;
+    }
+    Ok(())
+}
+
+/// How undecided the forest is about a sample, computed from its class scores. Both measures go
+/// from 0.0 for a unanimous vote to 1.0 for a vote that is split as evenly as possible
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Uncertainty{
+    /// One minus the difference between the two highest class scores, like ilastik's uncertainty layer
+    Margin,
+    /// Shannon entropy of the class scores, divided by its maximum of `ln(num_classes)`
+    Entropy,
+}
+
+impl Uncertainty{
+    /// The uncertainty of class scores like the ones from [`RandomForest::class_scores`]
+    pub fn of(&self, scores: &[f32]) -> f32{
+        match self{
+            Self::Margin => {
+                let (mut best, mut second) = (0.0f32, 0.0f32);
+                for score in scores{
+                    if *score > best{
+                        (best, second) = (*score, best);
+                    } else if *score > second{
+                        second = *score;
+                    }
+                }
+                1.0 - (best - second)
+            },
+            Self::Entropy => {
+                if scores.len() < 2{
+                    return 0.0
+                }
+                let entropy: f32 = scores.iter().filter(|score| **score > 0.0).map(|score| -score * score.ln()).sum();
+                entropy / (scores.len() as f32).ln()
+            },
+        }
+    }
+    /// Writes WGSL that declares `uncertainty: f32` from the `class_<k>_score` variables,
+    /// matching [`Self::of`]
+    pub fn write_wgsl(&self, out: &mut impl std::fmt::Write, num_classes: usize) -> Result<(), std::fmt::Error>{
+        match self{
+            Self::Margin => {
+                write!(out, "
+                var uncertainty_best_score = 0.0;
+                var uncertainty_second_score = 0.0;")?;
+                for class_idx in 0..num_classes{
+                    write!(out, "
+                if class_{class_idx}_score > uncertainty_best_score {{
+                    uncertainty_second_score = uncertainty_best_score;
+                    uncertainty_best_score = class_{class_idx}_score;
+                }} else if class_{class_idx}_score > uncertainty_second_score {{
+                    uncertainty_second_score = class_{class_idx}_score;
+                }}")?;
+                }
+                write!(out, "
+                let uncertainty = 1.0 - (uncertainty_best_score - uncertainty_second_score);")
+            },
+            Self::Entropy => {
+                write!(out, "
+                var uncertainty = 0.0;")?;
+                if num_classes < 2{
+                    return Ok(())
+                }
+                for class_idx in 0..num_classes{
+                    write!(out, "
+                if class_{class_idx}_score > 0.0 {{
+                    uncertainty -= class_{class_idx}_score * log(class_{class_idx}_score);
+                }}")?;
+                }
+                write!(out, "
+                uncertainty /= {:?};", (num_classes as f32).ln())
+            },
+        }
+    }
 }
 
 pub struct DecisionTree{
-    #[allow(dead_code)]
     root: TreeNode,
+    /// Length of the class distributions in the leaves, unless none of them had one
+    num_classes: Option<usize>,
+    /// Names from the `class = ...` lines of the DOT. Classes that were only referred to by index are missing
+    class_names: BTreeMap<usize, String>,
+}
+
+/// A node as declared in the DOT, before it is linked to its children
+enum ParsedNode{
+    Decision(Decision, Option<NodeStats>),
+    Prediction(Prediction),
 }
 
 impl DecisionTree{
     pub fn highest_class_idx(&self) -> usize{
         return self.root.highest_class_idx();
     }
-    pub fn highest_feature_idx(&self) -> usize{
-        return self.root.highest_feature_idx().unwrap();
+    /// `None` for trees that are a single leaf, which scikit emits for pure training sets
+    pub fn highest_feature_idx(&self) -> Option<usize>{
+        self.root.highest_feature_idx()
     }
-    pub fn parse(dot: &str) -> ah::Result<Self>{
+    pub fn parse(dot: &str) -> Result<Self, TreeError>{
         let graph: gs::Graph = gv::parse(dot)
-            .map_err(|s| ah::anyhow!("Could not parse the dot syntax: {s}"))?;
+            .map_err(|s| TreeError::Syntax(format!("Could not parse the dot syntax: {s}")))?;
         let gs::Graph::DiGraph { stmts,.. } = graph else {
-            ah::bail!("Expected directed graph");
+            return Err(TreeError::Syntax("Expected directed graph".to_owned()));
         };
 
         let edges: Vec<_> = stmts.iter()
@@ -161,124 +678,740 @@ impl DecisionTree{
                 _ => None
             })
             .map(parse_edge)
-            .collect::<ah::Result<_>>()?;
+            .collect::<Result<_, _>>()?;
+        let mut out_edges = HashMap::<u32, Vec<Edge>>::new();
+        for edge in &edges{
+            out_edges.entry(edge.origin).or_default().push(*edge);
+        }
 
-        let mut predictions = HashMap::<u32, Prediction>::new();
-        let mut decisions = HashMap::<u32, Decision>::new();
-        'stmts: for s in &stmts{
+        let mut nodes = HashMap::<u32, ParsedNode>::new();
+        let mut num_classes = None;
+        let mut class_names = BTreeMap::<usize, String>::new();
+        for s in &stmts{
             let gs::Stmt::Node(node) = s else {
                 continue;
             };
-            let node_id = node.id.0.to_string().parse::<u32>().context("Parsing node Id of {node:?}")?;
-            let is_leaf = edges.iter().find(|edge| edge.origin == node_id).is_none();
+            let node_id = parse_node_id(&node.id.0)?;
+            let bad_label = |message: String| TreeError::BadLabel{node_id, message};
             let Some(label_attr) = node.attributes.iter().find(|attr| attr.0.to_string() == "label") else {
-                ah::bail!("Node has no label {node:?}");
+                return Err(bad_label("Node has no label".to_owned()));
             };
-            let raw_label = label_attr.1.to_string();
-            let trimmed_label = raw_label.trim_matches('"');
-            for label_attr in trimmed_label.split("\\n"){
-                if is_leaf{
-                    let Some(prediction) = Prediction::try_parse_label_attr(&label_attr)? else {
-                        continue
-                    };
-                    predictions.insert(node_id, prediction);
-                    continue 'stmts
-                } else {
-                    let Some(decision) = Decision::try_parse_label_attr(&label_attr)? else {
-                        continue
-                    };
-                    decisions.insert(node_id, decision);
-                    continue 'stmts
+            let label_lines = label_lines(id_text(&label_attr.1));
+            let label_attrs: Vec<&str> = label_lines.iter().map(String::as_str).collect();
+            let stats = NodeStats::try_parse_label_attrs(&label_attrs).map_err(|e| bad_label(format!("{e:#}")))?;
+            let class_label = Prediction::try_parse_class_label(&label_attrs).map_err(|e| bad_label(format!("{e:#}")))?;
+            if let Some((class_idx, Some(name))) = class_label{
+                match class_names.get(&class_idx){
+                    Some(known) if *known != name => {
+                        return Err(TreeError::ClassNameConflict{class_idx, names: [known.clone(), name]})
+                    },
+                    Some(_) => (),
+                    None => { class_names.insert(class_idx, name); },
                 }
             }
-            ah::bail!("Could not parse statement {s:?}")
+            let decision = label_attrs.iter()
+                .find_map(|attr| Decision::try_parse_label_attr(attr).transpose())
+                .transpose()
+                .map_err(|e| bad_label(format!("{e:#}")))?;
+            let num_children = out_edges.get(&node_id).map_or(0, Vec::len);
+
+            let parsed = match (decision, num_children) {
+                (Some(decision), 2) => ParsedNode::Decision(decision, stats),
+                (Some(_), num_children) => return Err(TreeError::WrongChildCount{node_id, num_children}),
+                (None, 0) => {
+                    let prediction = Prediction::try_parse_label_attrs(&label_attrs)
+                        .map_err(|e| bad_label(format!("{e:#}")))?
+                        .ok_or_else(|| bad_label("Leaf has no class".to_owned()))?;
+                    if label_attrs.iter().any(|attr| attr.starts_with("value = ")){
+                        let found = prediction.probabilities.len();
+                        match num_classes {
+                            None => num_classes = Some(found),
+                            Some(expected) if expected != found => {
+                                return Err(TreeError::ClassCountMismatch{node_id, expected, found})
+                            },
+                            Some(_) => (),
+                        }
+                    }
+                    ParsedNode::Prediction(Prediction{stats, ..prediction})
+                },
+                (None, num_children) => return Err(bad_label(format!("Node has {num_children} children but no split"))),
+            };
+            if nodes.insert(node_id, parsed).is_some(){
+                return Err(TreeError::DuplicateNode(node_id));
+            }
+        }
+        if let Some(edge) = edges.iter().find(|edge| !nodes.contains_key(&edge.origin) || !nodes.contains_key(&edge.target)){
+            return Err(TreeError::DanglingEdge{origin: edge.origin, target: edge.target});
         }
 
         fn build_tree(
             node_id: u32,
-            edges: &[Edge],
-            predictions: &HashMap<u32, Prediction>,
-            decisions: &HashMap<u32, Decision>,
-        ) -> ah::Result<TreeNode>{
-            if let Some(pred) = predictions.get(&node_id){
-                return Ok(TreeNode::Prediction(*pred))
+            nodes: &HashMap<u32, ParsedNode>,
+            out_edges: &HashMap<u32, Vec<Edge>>,
+            visited: &mut HashSet<u32>,
+            path: &mut Vec<u32>,
+        ) -> Result<TreeNode, TreeError>{
+            if path.contains(&node_id){
+                return Err(TreeError::Cycle(node_id));
             }
-            if let Some(dec) = decisions.get(&node_id){
-                //FIXME: assume first edge is the "True" one. Is this reliable?
-                let out_edges: [Edge; 2] = edges.iter()
-                    .filter(|edge| edge.origin == node_id)
-                    .cloned()
-                    .collect::<Vec<Edge>>()
-                    .try_into()
-                    .map_err(|_| ah::anyhow!("Expected two edges from {node_id}"))?;
-                return Ok(TreeNode::Decision{
-                    decision: *dec,
-                    le_child: Box::new(build_tree(out_edges[0].target, edges, predictions, decisions)?),
-                    gt_child: Box::new(build_tree(out_edges[1].target, edges, predictions, decisions)?),
-                })
+            if !visited.insert(node_id){
+                return Err(TreeError::MultipleParents(node_id));
             }
-            ah::bail!("Could not find node with id {node_id}");
+            match &nodes[&node_id] {
+                ParsedNode::Prediction(pred) => Ok(TreeNode::Prediction(pred.clone())),
+                ParsedNode::Decision(decision, stats) => {
+                    let (le_target, gt_target) = split_out_edges(node_id, [out_edges[&node_id][0], out_edges[&node_id][1]])?;
+                    path.push(node_id);
+                    let le_child = build_tree(le_target, nodes, out_edges, visited, path)?;
+                    let gt_child = build_tree(gt_target, nodes, out_edges, visited, path)?;
+                    path.pop();
+                    Ok(TreeNode::Decision{
+                        decision: *decision,
+                        le_child: Box::new(le_child),
+                        gt_child: Box::new(gt_child),
+                        stats: stats.clone(),
+                    })
+                },
+            }
+        }
+
+        if !nodes.contains_key(&0){
+            return Err(TreeError::MissingRoot);
         }
+        let mut visited = HashSet::new();
+        let root = build_tree(0, &nodes, &out_edges, &mut visited, &mut vec![])?;
+        if let Some(node_id) = nodes.keys().filter(|node_id| !visited.contains(node_id)).min(){
+            return Err(TreeError::UnreachableNode(*node_id));
+        }
+        Ok(Self{root, num_classes, class_names})
+    }
 
-        let root = build_tree(0, &edges, &predictions, &decisions)?;
-        let out = Ok(Self{root});
-        out
+    /// Writes the tree as nested `if/else` statements. `feature_exprs[i]` is the WGSL expression
+    /// holding the value of feature `i`
+    pub fn write_wgsl(
+        &self, out: &mut impl std::fmt::Write, voting: VotingMode, feature_exprs: &[String]
+    ) -> Result<(), std::fmt::Error> {
+        self.root.write_wgsl(out, 0, voting, feature_exprs)
+    }
+    /// Renders the tree as DOT in the layout of scikit's `export_graphviz(node_ids=True)`,
+    /// which [`Self::parse`] reads back into the same tree
+    pub fn to_dot(&self) -> String{
+        self.to_dot_with_class_names(&|class_idx| match self.class_names.get(&class_idx){
+            Some(name) => name.clone(),
+            None => class_idx.to_string(),
+        })
     }
+    fn to_dot_with_class_names(&self, class_name: &dyn Fn(usize) -> String) -> String{
+        let mut dot = String::new();
+        dot += "digraph Tree {\n";
+        dot += "node [shape=box, fontname=\"helvetica\"] ;\n";
+        dot += "edge [fontname=\"helvetica\"] ;\n";
+        self.root.write_dot(&mut dot, &mut 0, None, class_name).unwrap();
+        dot += "}\n";
+        dot
+    }
+}
+
+/// An entry of a forest's class table
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassInfo{
+    /// Position of the class in the forest's class distributions, and what `predicted_class` holds for it
+    pub index: usize,
+    /// The name from the DOT files or training labels, like `membrane`. Defaults to the index
+    pub name: String,
+    /// RGBA color to show the class in. Outputs pick one themselves if this is `None`
+    pub color: Option<[u8; 4]>,
+}
 
-    pub fn write_wgsl(&self, out: &mut impl std::fmt::Write) -> Result<(), std::fmt::Error> {
-        self.root.write_wgsl(out, 0)
+impl ClassInfo{
+    /// A class that is only known by its index
+    pub fn unnamed(index: usize) -> Self{
+        Self{index, name: index.to_string(), color: None}
     }
 }
 
 pub struct RandomForest{
     #[allow(dead_code)]
     trees: Vec<DecisionTree>,
-    #[allow(dead_code)]
-    highest_class_idx: usize,
+    classes: Vec<ClassInfo>,
     highest_feature_idx: usize,
+    feature_spec: Option<FeatureSpec>,
 }
 
 impl RandomForest{
     pub fn highest_feature_idx(&self) -> usize{
         self.highest_feature_idx
     }
-    pub fn write_wgsl(&self, out: &mut impl std::fmt::Write) -> Result<(), std::fmt::Error> {
-        for class_idx in 0..=self.highest_class_idx{
-            write!(out, "var class_{class_idx}_score: u32 = 0;\n")?;
+    pub fn num_classes(&self) -> usize{
+        self.classes.len()
+    }
+    /// The class table, with entry `i` describing class index `i`
+    pub fn classes(&self) -> &[ClassInfo]{
+        &self.classes
+    }
+    pub fn class_name(&self, class_idx: usize) -> &str{
+        &self.classes[class_idx].name
+    }
+    /// Replaces the class table, e.g. to give the classes names and colors after training
+    pub fn with_class_table(mut self, classes: Vec<ClassInfo>) -> Result<Self, ForestError>{
+        if classes.len() != self.num_classes(){
+            return Err(ForestError::BadClassTable(format!(
+                "forest has {} classes, but the table has {} entries", self.num_classes(), classes.len()
+            )));
         }
+        if let Some((position, class)) = classes.iter().enumerate().find(|(position, class)| class.index != *position){
+            return Err(ForestError::BadClassTable(format!("entry #{position} is for class {}", class.index)));
+        }
+        self.classes = classes;
+        Ok(self)
+    }
+    /// Indices of the features that some tree splits on. Features outside of this set never
+    /// influence a prediction, so they don't need to be computed
+    pub fn used_features(&self) -> BTreeSet<usize>{
+        let mut used_features = BTreeSet::new();
         for tree in &self.trees{
-            tree.write_wgsl(out)?
+            tree.root.collect_used_features(&mut used_features);
+        }
+        used_features
+    }
+    /// Evaluates the forest on the CPU, producing the same values that the shader from
+    /// [`Self::write_wgsl`] leaves in its `class_<k>_score` variables
+    pub fn class_scores(&self, features: &[f32], voting: VotingMode) -> Vec<f32>{
+        assert!(
+            features.len() > self.highest_feature_idx,
+            "Forest needs {} features, got {}", self.highest_feature_idx + 1, features.len()
+        );
+        let mut scores = vec![0.0f32; self.num_classes()];
+        for tree in &self.trees{
+            let leaf = tree.root.leaf_for(features);
+            match voting {
+                VotingMode::Hard => scores[leaf.class] += 1.0,
+                VotingMode::Soft => for (score, probability) in scores.iter_mut().zip(&leaf.probabilities) {
+                    *score += probability;
+                },
+            }
+        }
+        let num_trees = self.trees.len() as f32;
+        scores.iter_mut().for_each(|score| *score /= num_trees);
+        scores
+    }
+    /// Class probabilities for a single sample, like scikit's `predict_proba`
+    pub fn predict_proba(&self, features: &[f32]) -> Vec<f32>{
+        self.class_scores(features, VotingMode::Soft)
+    }
+    /// The most likely class for a single sample, like scikit's `predict`. Ties go to the lowest class index
+    pub fn predict(&self, features: &[f32]) -> usize{
+        argmax(&self.predict_proba(features))
+    }
+    /// Writes WGSL that declares a `class_<k>_score: f32` variable for every class and fills it
+    /// with the fraction of the forest's vote that went to class `k`, as counted by `voting`.
+    /// `feature_exprs[i]` is the WGSL expression holding the value of feature `i`
+    pub fn write_wgsl(
+        &self, out: &mut impl std::fmt::Write, voting: VotingMode, feature_exprs: &[String]
+    ) -> Result<(), std::fmt::Error> {
+        for class_idx in 0..self.num_classes(){
+            writeln!(out, "var class_{class_idx}_score: f32 = 0.0;")?;
+        }
+        for tree in &self.trees{
+            tree.write_wgsl(out, voting, feature_exprs)?
+        }
+        let num_trees = self.trees.len();
+        for class_idx in 0..self.num_classes(){
+            writeln!(out, "class_{class_idx}_score /= {num_trees}.0;")?;
         }
         Ok(())
     }
-    pub fn from_dir(dir_name: &str) -> ah::Result<Self>{
-        let mut trees = Vec::new();
-        for entry in std::fs::read_dir(dir_name).context(format!("Opening dir {dir_name}"))? {
-            let entry = entry.context("reading dir entry")?;
-            if !entry.file_type()?.is_file(){
-                continue
+    /// Flattens every tree into a single node array, so the forest can be uploaded to the GPU
+    /// as data instead of being compiled into the shader
+    pub fn flatten(&self) -> FlatForest{
+        let mut flat = FlatForest{
+            roots: Vec::with_capacity(self.trees.len()),
+            nodes: vec![],
+            leaf_probabilities: vec![],
+            num_classes: self.num_classes(),
+        };
+        for tree in &self.trees{
+            let root_idx = tree.root.flatten_into(&mut flat);
+            flat.roots.push(root_idx);
+        }
+        flat
+    }
+    /// Rebuilds a forest from its flattened form, checking that every tree is well-formed
+    pub fn from_flat(flat: &FlatForest) -> ah::Result<Self>{
+        fn build_node(flat: &FlatForest, node_idx: u32) -> ah::Result<TreeNode>{
+            let Some(node) = flat.nodes.get(node_idx as usize) else {
+                ah::bail!("Node {node_idx} is out of bounds");
+            };
+            if node.feature_idx == FlatNode::LEAF{
+                let class = node.gt_child as usize;
+                if class >= flat.num_classes{
+                    ah::bail!("Leaf {node_idx} votes for class {class}, but there are only {} classes", flat.num_classes);
+                }
+                let Some(probabilities) = flat.leaf_probabilities.get(node.le_child as usize..)
+                    .and_then(|probabilities| probabilities.get(..flat.num_classes)) else {
+                    ah::bail!("Class distribution of leaf {node_idx} is out of bounds");
+                };
+                return Ok(TreeNode::Prediction(Prediction{class, probabilities: probabilities.to_vec(), stats: None}))
             }
+            // nodes are stored in pre-order, so this also rules out cycles
+            if node.le_child <= node_idx || node.gt_child <= node_idx{
+                ah::bail!("Children of node {node_idx} don't come after it");
+            }
+            Ok(TreeNode::Decision{
+                decision: Decision{feature_idx: node.feature_idx as usize, threshold: node.threshold},
+                le_child: Box::new(build_node(flat, node.le_child)?),
+                gt_child: Box::new(build_node(flat, node.gt_child)?),
+                stats: None,
+            })
+        }
+        let trees = flat.roots.iter()
+            .map(|root| Ok(DecisionTree{
+                root: build_node(flat, *root)?,
+                num_classes: Some(flat.num_classes),
+                class_names: BTreeMap::new(),
+            }))
+            .collect::<ah::Result<Vec<_>>>()?;
+        let mut forest = Self::from_trees(trees)?;
+        // leaves may all favor a subset of the classes, but the distributions say how many there are
+        for class_idx in forest.num_classes()..flat.num_classes{
+            forest.classes.push(ClassInfo::unnamed(class_idx));
+        }
+        Ok(forest)
+    }
+    /// Writes every tree to `<dir_name>/tree_<i>.txt` as DOT, the layout [`Self::from_dir`] reads
+    pub fn export_dir(&self, dir_name: &str) -> ah::Result<()>{
+        std::fs::create_dir_all(dir_name).context(format!("Creating dir {dir_name}"))?;
+        for (tree_idx, tree) in self.trees.iter().enumerate(){
+            let path = std::path::Path::new(dir_name).join(format!("tree_{tree_idx}.txt"));
+            let dot = tree.to_dot_with_class_names(&|class_idx| self.class_name(class_idx).to_owned());
+            std::fs::write(&path, dot).context(format!("Writing {}", path.display()))?;
+        }
+        Ok(())
+    }
+    /// Loads every `tree_<i>.*` file in `dir_name` in the order of `i`. Names with several numbers,
+    /// like `tree_3_7.txt`, are ordered by each number in turn. Other files are skipped with a warning
+    pub fn from_dir(dir_name: &str) -> Result<Self, ForestError>{
+        let io_error = |path: &std::path::Path| {
+            let path = path.to_owned();
+            move |source| ForestError::Io{path, source}
+        };
+        let dir_path = std::path::Path::new(dir_name);
+        let mut tree_paths = Vec::<(Vec<u64>, PathBuf)>::new();
+        for entry in std::fs::read_dir(dir_path).map_err(io_error(dir_path))? {
+            let entry = entry.map_err(io_error(dir_path))?;
             let path = entry.path();
-            let file_name = path.file_name().ok_or(ah::anyhow!("File had no file_name"))?;
-            if !file_name.to_string_lossy().starts_with("tree_"){
+            if !entry.file_type().map_err(io_error(&path))?.is_file(){
                 continue
             }
-            let raw_tree = std::fs::read_to_string(&path).context("reading file")?;
-            let tree = DecisionTree::parse(&raw_tree).context("parsing tree")?;
-            trees.push(tree)
+            let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let Some(raw_index) = file_stem.strip_prefix("tree_") else {
+                log::warn!("Skipping {}: not a tree_<i> file", path.display());
+                continue
+            };
+            let Ok(index) = raw_index.split('_').map(str::parse::<u64>).collect::<Result<Vec<_>, _>>() else {
+                return Err(ForestError::BadFileName(path));
+            };
+            tree_paths.push((index, path));
+        }
+        tree_paths.sort();
+        if let Some(pair) = tree_paths.windows(2).find(|pair| pair[0].0 == pair[1].0){
+            return Err(ForestError::DuplicateTreeIndex(pair[0].1.clone(), pair[1].1.clone()));
         }
 
+        let mut trees = Vec::with_capacity(tree_paths.len());
+        for (_, path) in tree_paths{
+            let raw_tree = std::fs::read_to_string(&path).map_err(io_error(&path))?;
+            let tree = DecisionTree::parse(&raw_tree).map_err(|source| ForestError::Tree{path, source})?;
+            trees.push(tree)
+        }
+        Self::from_trees(trees)
+    }
+    pub fn from_trees(trees: Vec<DecisionTree>) -> Result<Self, ForestError>{
         let highest_class_idx = trees.iter()
             .map(|t| t.highest_class_idx())
             .max()
-            .ok_or(ah::anyhow!("Gettin forest num classes"))?;
+            .ok_or(ForestError::NoTrees)?;
+
+        let mut num_classes = None;
+        for (tree_idx, tree) in trees.iter().enumerate(){
+            match (num_classes, tree.num_classes) {
+                (None, found) => num_classes = found,
+                (Some(expected), Some(found)) if expected != found => {
+                    return Err(ForestError::ClassCountMismatch{tree_idx, expected, found})
+                },
+                _ => (),
+            }
+        }
+
+        let mut classes: Vec<ClassInfo> = (0..=highest_class_idx).map(ClassInfo::unnamed).collect();
+        let mut named_classes = BTreeSet::new();
+        for (tree_idx, tree) in trees.iter().enumerate(){
+            for (class_idx, name) in &tree.class_names{
+                let class = &mut classes[*class_idx];
+                if named_classes.insert(*class_idx){
+                    class.name = name.clone();
+                } else if class.name != *name {
+                    return Err(ForestError::ClassNameConflict{
+                        tree_idx, class_idx: *class_idx, names: [class.name.clone(), name.clone()]
+                    });
+                }
+            }
+        }
 
         let highest_feature_idx = trees.iter()
-            .map(|t| t.highest_feature_idx())
+            .filter_map(|t| t.highest_feature_idx())
             .max()
-            .ok_or(ah::anyhow!("Getting forest highest feature idx"))?;
+            .unwrap_or(0);
+
+        Ok(Self{trees, classes, highest_feature_idx, feature_spec: None})
+    }
+    /// Attaches the description of the features the forest was trained on, failing if some tree
+    /// splits on a feature that `feature_spec` doesn't have
+    pub fn with_feature_spec(mut self, feature_spec: FeatureSpec) -> Result<Self, ForestError>{
+        if let Some(feature_idx) = self.used_features().into_iter().find(|idx| *idx >= feature_spec.len()){
+            return Err(ForestError::UnknownFeature{feature_idx, num_features: feature_spec.len()});
+        }
+        self.feature_spec = Some(feature_spec);
+        Ok(self)
+    }
+    pub fn feature_spec(&self) -> Option<&FeatureSpec>{
+        self.feature_spec.as_ref()
+    }
+    /// Trains a forest of Gini-impurity CART trees, like scikit's `RandomForestClassifier.fit`.
+    ///
+    /// `features` is a row-major `(num_samples, num_features)` matrix and `labels` holds the class
+    /// index of each sample, so the forest will have `max(labels) + 1` classes
+    pub fn train(features: &[f32], labels: &[usize], params: &TrainingParams) -> ah::Result<Self>{
+        if labels.is_empty() || !features.len().is_multiple_of(labels.len()) {
+            ah::bail!("Can't split {} feature values between {} samples", features.len(), labels.len());
+        }
+        if features.len() / labels.len() == 0 {
+            ah::bail!("Samples need at least one feature");
+        }
+        if params.num_trees == 0 || params.min_samples_leaf == 0 {
+            ah::bail!("Bad training parameters: {params:?}");
+        }
+        let training_set = TrainingSet{
+            features,
+            labels,
+            num_features: features.len() / labels.len(),
+            num_classes: labels.iter().max().unwrap() + 1,
+        };
+
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let tree_seeds: Vec<u64> = (0..params.num_trees).map(|_| rng.random()).collect();
+        let num_threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let trees: Vec<DecisionTree> = std::thread::scope(|scope| {
+            let training_set = &training_set;
+            let workers: Vec<_> = tree_seeds.chunks(tree_seeds.len().div_ceil(num_threads))
+                .map(|seeds| scope.spawn(move || {
+                    seeds.iter().map(|seed| training_set.grow_tree(params, *seed)).collect::<Vec<_>>()
+                }))
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+
+        Ok(Self::from_trees(trees)?)
+    }
+    /// Trains on arrays as they come out of numpy: `features` of shape `(num_samples, num_features)`
+    /// and `labels` of shape `(num_samples,)`. Like scikit, distinct label values become classes in
+    /// ascending order and are used as the class names. They are also returned alongside the forest
+    /// so predictions can be mapped back
+    pub fn train_npy<L: NpyElement + PartialOrd + std::fmt::Display>(
+        features: &NpyArray<f32>, labels: &NpyArray<L>, params: &TrainingParams
+    ) -> ah::Result<(Self, Vec<L>)>{
+        let (&[num_samples, _], &[num_labels]) = (features.shape(), labels.shape()) else {
+            ah::bail!("Expected 2D features and 1D labels, got shapes {:?} and {:?}", features.shape(), labels.shape());
+        };
+        if num_samples != num_labels{
+            ah::bail!("Got {num_samples} samples but {num_labels} labels");
+        }
+        let mut classes: Vec<L> = vec![];
+        for label in labels.data(){
+            if label.partial_cmp(label).is_none(){
+                ah::bail!("Labels must be comparable");
+            }
+            if let Err(idx) = classes.binary_search_by(|class| class.partial_cmp(label).unwrap()){
+                classes.insert(idx, *label);
+            }
+        }
+        let class_idxs: Vec<usize> = labels.data().iter()
+            .map(|label| classes.binary_search_by(|class| class.partial_cmp(label).unwrap()).unwrap())
+            .collect();
+        let mut forest = Self::train(features.data(), &class_idxs, params)?;
+        for (class, label) in forest.classes.iter_mut().zip(&classes){
+            class.name = label.to_string();
+        }
+        Ok((forest, classes))
+    }
+    /// [`Self::predict_proba`] for every row of `features`, which has shape `(num_samples, num_features)`.
+    /// The result has shape `(num_samples, num_classes)`
+    pub fn predict_proba_npy(&self, features: &NpyArray<f32>) -> ah::Result<NpyArray<f32>>{
+        let num_samples = self.check_npy_samples(features)?;
+        let probabilities = self.sample_rows(features).flat_map(|sample| self.predict_proba(sample)).collect();
+        NpyArray::new(vec![num_samples, self.num_classes()], probabilities)
+    }
+    /// [`Self::predict`] for every row of `features`, which has shape `(num_samples, num_features)`
+    pub fn predict_npy(&self, features: &NpyArray<f32>) -> ah::Result<NpyArray<u32>>{
+        let num_samples = self.check_npy_samples(features)?;
+        let class_idxs = self.sample_rows(features).map(|sample| self.predict(sample) as u32).collect();
+        NpyArray::new(vec![num_samples], class_idxs)
+    }
+    /// The number of samples in `features`, if it is a 2D array with every feature the forest splits on
+    fn check_npy_samples(&self, features: &NpyArray<f32>) -> ah::Result<usize>{
+        let &[num_samples, num_features] = features.shape() else {
+            ah::bail!("Expected 2D features, got shape {:?}", features.shape());
+        };
+        if num_features <= self.highest_feature_idx{
+            ah::bail!("Forest splits on feature {}, but samples only have {num_features} features", self.highest_feature_idx);
+        }
+        Ok(num_samples)
+    }
+    fn sample_rows<'a>(&self, features: &'a NpyArray<f32>) -> impl Iterator<Item = &'a [f32]>{
+        features.data().chunks_exact(features.shape()[1])
+    }
+}
+
+/// Hyperparameters for [`RandomForest::train`], named after their scikit counterparts
+#[derive(Debug, Clone)]
+pub struct TrainingParams{
+    pub num_trees: usize,
+    /// Trees stop growing at this depth. `None` grows them until leaves are pure
+    pub max_depth: Option<usize>,
+    /// A split is only considered if it leaves at least this many samples on each side
+    pub min_samples_leaf: usize,
+    /// How many randomly picked features each split considers. `None` means `sqrt(num_features)`
+    pub max_features: Option<usize>,
+    /// Train each tree on a sample of the training set drawn with replacement
+    pub bootstrap: bool,
+    pub seed: u64,
+}
 
-        Ok(Self{trees, highest_class_idx, highest_feature_idx})
+impl Default for TrainingParams{
+    fn default() -> Self {
+        Self{
+            num_trees: 100,
+            max_depth: None,
+            min_samples_leaf: 1,
+            max_features: None,
+            bootstrap: true,
+            seed: 0,
+        }
+    }
+}
+
+struct TrainingSet<'a>{
+    features: &'a [f32],
+    labels: &'a [usize],
+    num_features: usize,
+    num_classes: usize,
+}
+
+struct Split{
+    feature_idx: usize,
+    threshold: f32,
+    /// Sum over both sides of `sum(class_count^2) / side_count`. Maximizing it minimizes the
+    /// weighted Gini impurity of the children
+    score: f32,
+}
+
+impl TrainingSet<'_>{
+    fn feature(&self, sample_idx: usize, feature_idx: usize) -> f32{
+        self.features[sample_idx * self.num_features + feature_idx]
+    }
+    fn class_counts(&self, samples: &[usize]) -> Vec<f32>{
+        let mut counts = vec![0.0; self.num_classes];
+        for sample_idx in samples{
+            counts[self.labels[*sample_idx]] += 1.0;
+        }
+        counts
+    }
+    fn grow_tree(&self, params: &TrainingParams, seed: u64) -> DecisionTree{
+        let mut rng = StdRng::seed_from_u64(seed);
+        let num_samples = self.labels.len();
+        let mut samples: Vec<usize> = if params.bootstrap {
+            (0..num_samples).map(|_| rng.random_range(0..num_samples)).collect()
+        } else {
+            (0..num_samples).collect()
+        };
+        DecisionTree{
+            root: self.grow_node(&mut samples, 0, params, &mut rng),
+            num_classes: Some(self.num_classes),
+            class_names: BTreeMap::new(),
+        }
+    }
+    fn grow_node(&self, samples: &mut [usize], depth: usize, params: &TrainingParams, rng: &mut StdRng) -> TreeNode{
+        let counts = self.class_counts(samples);
+        let stats = Some(NodeStats{samples: samples.len(), value: counts.clone()});
+        let is_pure = counts.iter().filter(|count| **count > 0.0).count() <= 1;
+        let reached_max_depth = params.max_depth.is_some_and(|max_depth| depth >= max_depth);
+        let split = if is_pure || reached_max_depth || samples.len() < 2 * params.min_samples_leaf {
+            None
+        } else {
+            self.best_split(samples, params, rng)
+        };
+        let Some(split) = split else {
+            let total: f32 = counts.iter().sum();
+            return TreeNode::Prediction(Prediction{
+                class: argmax(&counts),
+                probabilities: counts.iter().map(|count| count / total).collect(),
+                stats,
+            })
+        };
+
+        let mut num_le = 0;
+        for i in 0..samples.len(){
+            if self.feature(samples[i], split.feature_idx) <= split.threshold{
+                samples.swap(i, num_le);
+                num_le += 1;
+            }
+        }
+        let (le_samples, gt_samples) = samples.split_at_mut(num_le);
+        TreeNode::Decision{
+            decision: Decision{feature_idx: split.feature_idx, threshold: split.threshold},
+            le_child: Box::new(self.grow_node(le_samples, depth + 1, params, rng)),
+            gt_child: Box::new(self.grow_node(gt_samples, depth + 1, params, rng)),
+            stats,
+        }
+    }
+    fn best_split(&self, samples: &[usize], params: &TrainingParams, rng: &mut StdRng) -> Option<Split>{
+        let max_features = params.max_features
+            .unwrap_or((self.num_features as f64).sqrt() as usize)
+            .clamp(1, self.num_features);
+        let min_samples_leaf = params.min_samples_leaf;
+        let num_samples = samples.len();
+        let mut best: Option<Split> = None;
+        let mut sorted: Vec<(f32, usize)> = Vec::with_capacity(num_samples);
+
+        for feature_idx in rand::seq::index::sample(rng, self.num_features, max_features){
+            sorted.clear();
+            sorted.extend(samples.iter().map(|s| (self.feature(*s, feature_idx), self.labels[*s])));
+            sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            let mut le_counts = vec![0.0f32; self.num_classes];
+            let mut gt_counts = self.class_counts(samples);
+            for (num_le, window) in (1..).zip(sorted.windows(2)){
+                let [(value, label), (next_value, _)] = [window[0], window[1]];
+                le_counts[label] += 1.0;
+                gt_counts[label] -= 1.0;
+                if value == next_value || num_le < min_samples_leaf || num_samples - num_le < min_samples_leaf{
+                    continue
+                }
+                let sum_of_squares = |counts: &[f32]| counts.iter().map(|c| c * c).sum::<f32>();
+                let score = sum_of_squares(&le_counts) / num_le as f32
+                    + sum_of_squares(&gt_counts) / (num_samples - num_le) as f32;
+                if best.as_ref().is_some_and(|best| best.score >= score){
+                    continue
+                }
+                // like scikit, split halfway between neighboring values, unless rounding would
+                // make the threshold swallow the upper value
+                let mut threshold = value / 2.0 + next_value / 2.0;
+                if threshold >= next_value || threshold < value {
+                    threshold = value;
+                }
+                best = Some(Split{feature_idx, threshold, score});
+            }
+        }
+        best
+    }
+}
+
+/// A node of a [`FlatForest`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FlatNode{
+    /// The feature to compare against `threshold`, or [`FlatNode::LEAF`]
+    pub feature_idx: u32,
+    pub threshold: f32,
+    /// Index of the `<=` child or, for leaves, the offset of the leaf's class distribution in
+    /// [`FlatForest::leaf_probabilities`]
+    pub le_child: u32,
+    /// Index of the `>` child or, for leaves, the class the leaf votes for
+    pub gt_child: u32,
+}
+
+impl FlatNode{
+    pub const LEAF: u32 = u32::MAX;
+}
+
+/// A [`RandomForest`] as plain arrays. Each tree is stored in pre-order starting at its entry in `roots`,
+/// and every leaf owns `num_classes` entries in `leaf_probabilities`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatForest{
+    pub roots: Vec<u32>,
+    pub nodes: Vec<FlatNode>,
+    pub leaf_probabilities: Vec<f32>,
+    pub num_classes: usize,
+}
+
+impl FlatForest{
+    /// Number of `u32` words in the header of [`Self::to_words`] before the tree roots
+    pub const HEADER_LEN: usize = 2;
+    /// Number of `u32` words each node takes in [`Self::to_words`]
+    pub const NODE_LEN: usize = 4;
+
+    /// Serializes the forest into the layout the GPU traversal loop expects:
+    /// `[num_trees, num_nodes, roots..., nodes..., leaf_probabilities...]`, where each node is
+    /// `[feature_idx, threshold, le_child, gt_child]` and floats are stored as their bits
+    pub fn to_words(&self) -> Vec<u32>{
+        let mut words = Vec::with_capacity(
+            Self::HEADER_LEN + self.roots.len() + self.nodes.len() * Self::NODE_LEN + self.leaf_probabilities.len()
+        );
+        words.push(self.roots.len() as u32);
+        words.push(self.nodes.len() as u32);
+        words.extend(&self.roots);
+        for node in &self.nodes{
+            words.extend([node.feature_idx, node.threshold.to_bits(), node.le_child, node.gt_child]);
+        }
+        words.extend(self.leaf_probabilities.iter().map(|p| p.to_bits()));
+        words
+    }
+    /// The inverse of [`Self::to_words`]. Only checks that the layout adds up; use
+    /// [`RandomForest::from_flat`] to check the trees themselves
+    pub fn from_words(words: &[u32], num_classes: usize) -> ah::Result<Self>{
+        let [num_trees, num_nodes, rest @ ..] = words else {
+            ah::bail!("Forest words are missing the header");
+        };
+        let (num_trees, num_nodes) = (*num_trees as usize, *num_nodes as usize);
+        let Some((roots, rest)) = rest.split_at_checked(num_trees) else {
+            ah::bail!("Expected {num_trees} tree roots, got {} words", rest.len());
+        };
+        let Some((raw_nodes, raw_probabilities)) = rest.split_at_checked(num_nodes * Self::NODE_LEN) else {
+            ah::bail!("Expected {num_nodes} nodes, got {} words", rest.len());
+        };
+        let nodes = raw_nodes.chunks_exact(Self::NODE_LEN)
+            .map(|node| FlatNode{
+                feature_idx: node[0], threshold: f32::from_bits(node[1]), le_child: node[2], gt_child: node[3]
+            })
+            .collect();
+        Ok(Self{
+            roots: roots.to_vec(),
+            nodes,
+            leaf_probabilities: raw_probabilities.iter().map(|bits| f32::from_bits(*bits)).collect(),
+            num_classes,
+        })
+    }
+    /// Same as [`RandomForest::class_scores`], but walking the flattened nodes like the GPU does
+    pub fn class_scores(&self, features: &[f32], voting: VotingMode) -> Vec<f32>{
+        let mut scores = vec![0.0f32; self.num_classes];
+        for root in &self.roots{
+            let mut node = self.nodes[*root as usize];
+            while node.feature_idx != FlatNode::LEAF{
+                let next_idx = if features[node.feature_idx as usize] <= node.threshold { node.le_child } else { node.gt_child };
+                node = self.nodes[next_idx as usize];
+            }
+            match voting {
+                VotingMode::Hard => scores[node.gt_child as usize] += 1.0,
+                VotingMode::Soft => {
+                    let probabilities = &self.leaf_probabilities[node.le_child as usize..][..self.num_classes];
+                    for (score, probability) in scores.iter_mut().zip(probabilities) {
+                        *score += probability;
+                    }
+                },
+            }
+        }
+        let num_trees = self.roots.len() as f32;
+        scores.iter_mut().for_each(|score| *score /= num_trees);
+        scores
     }
 }
 
@@ -301,3 +1434,482 @@ fn test_decision_tree_parsing(){
         }
     "#).unwrap();
 }
+
+#[cfg(test)]
+fn leaf_class(node: &TreeNode) -> usize{
+    match node{
+        TreeNode::Prediction(pred) => pred.class,
+        TreeNode::Decision { .. } => panic!("Expected a leaf, found {node:?}"),
+    }
+}
+
+#[test]
+fn test_decision_tree_parsing_honors_edge_labels(){
+    // Edges out of the root are listed in reverse order, but labeled
+    let dt = DecisionTree::parse(r#"
+        digraph Tree {
+            0 [label="node #0\nx[3] <= 1.5\nsamples = 10\nvalue = [5, 5]\nclass = 0"] ;
+            2 [label="node #2\nsamples = 5\nvalue = [0, 5]\nclass = 1"] ;
+            0 -> 2 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;
+            1 [label="node #1\nsamples = 5\nvalue = [5, 0]\nclass = 0"] ;
+            0 -> 1 [labeldistance=2.5, labelangle=45, headlabel="True"] ;
+        }
+    "#).unwrap();
+    let TreeNode::Decision { le_child, gt_child, .. } = &dt.root else {
+        panic!("Expected root to be a decision");
+    };
+    assert_eq!(leaf_class(le_child), 0);
+    assert_eq!(leaf_class(gt_child), 1);
+}
+
+#[test]
+fn test_decision_tree_parsing_uses_node_id_layout(){
+    // No labels, and the '<=' child (node #1) is listed last
+    let dt = DecisionTree::parse(r#"
+        digraph Tree {
+            0 [label="node #0\nx[3] <= 1.5\nsamples = 10\nvalue = [5, 5]\nclass = 0"] ;
+            2 [label="node #2\nsamples = 5\nvalue = [0, 5]\nclass = 1"] ;
+            0 -> 2 ;
+            1 [label="node #1\nsamples = 5\nvalue = [5, 0]\nclass = 0"] ;
+            0 -> 1 ;
+        }
+    "#).unwrap();
+    let TreeNode::Decision { le_child, gt_child, .. } = &dt.root else {
+        panic!("Expected root to be a decision");
+    };
+    assert_eq!(leaf_class(le_child), 0);
+    assert_eq!(leaf_class(gt_child), 1);
+}
+
+#[test]
+fn test_decision_tree_parsing_rejects_ambiguous_edges(){
+    let err = DecisionTree::parse(r#"
+        digraph Tree {
+            0 [label="node #0\nx[3] <= 1.5\nsamples = 10\nvalue = [5, 5]\nclass = 0"] ;
+            5 [label="node #5\nsamples = 5\nvalue = [5, 0]\nclass = 0"] ;
+            0 -> 5 ;
+            7 [label="node #7\nsamples = 5\nvalue = [0, 5]\nclass = 1"] ;
+            0 -> 7 ;
+        }
+    "#);
+    assert!(matches!(err, Err(TreeError::AmbiguousBranches{node_id: 0, ..})));
+
+    let err = DecisionTree::parse(r#"
+        digraph Tree {
+            0 [label="node #0\nx[3] <= 1.5\nsamples = 10\nvalue = [5, 5]\nclass = 0"] ;
+            1 [label="node #1\nsamples = 5\nvalue = [5, 0]\nclass = 0"] ;
+            0 -> 1 [headlabel="True"] ;
+            2 [label="node #2\nsamples = 5\nvalue = [0, 5]\nclass = 1"] ;
+            0 -> 2 [headlabel="True"] ;
+        }
+    "#);
+    assert!(matches!(err, Err(TreeError::AmbiguousBranches{node_id: 0, ..})));
+}
+
+#[cfg(test)]
+pub const TWO_LEVEL_TREE_DOT: &str = r#"
+    digraph Tree {
+        node [shape=box, fontname="helvetica"] ;
+        edge [fontname="helvetica"] ;
+        0 [label="node #0\nx[4] <= 33.38113975524902\nsamples = 88\nvalue = [68, 71]\nclass = 1"] ;
+        1 [label="node #1\nx[29] <= 0.9547376930713652\nsamples = 49\nvalue = [66, 11]\nclass = 0"] ;
+        0 -> 1 [labeldistance=2.5, labelangle=45, headlabel="True"] ;
+        2 [label="node #2\nsamples = 44\nvalue = [66, 0]\nclass = 0"] ;
+        1 -> 2 ;
+        3 [label="node #3\nsamples = 5\nvalue = [0, 11]\nclass = 1"] ;
+        1 -> 3 ;
+        4 [label="node #4\nsamples = 39\nvalue = [2.0, 6.000000000000001]\nclass = 1"] ;
+        0 -> 4 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;
+    }
+"#;
+
+#[test]
+fn test_leaf_values_are_normalized(){
+    let dt = DecisionTree::parse(TWO_LEVEL_TREE_DOT).unwrap();
+    let TreeNode::Decision { gt_child, .. } = &dt.root else {
+        panic!("Expected root to be a decision");
+    };
+    let TreeNode::Prediction(pred) = gt_child.as_ref() else {
+        panic!("Expected a leaf");
+    };
+    assert_eq!(pred.class, 1);
+    assert!((pred.probabilities[0] - 0.25).abs() < 1e-6);
+    assert!((pred.probabilities[1] - 0.75).abs() < 1e-6);
+}
+
+#[test]
+fn test_forest_wgsl_voting_modes(){
+    let forest = RandomForest::from_trees(vec![DecisionTree::parse(TWO_LEVEL_TREE_DOT).unwrap()]).unwrap();
+    let feature_exprs: Vec<String> = (0..30).map(|idx| format!("feature_{}[{}]", idx / 3, idx % 3)).collect();
+    for voting in [VotingMode::Hard, VotingMode::Soft]{
+        let mut code = String::new();
+        code += "fn classify(feature_1: vec3<f32>, feature_9: vec3<f32>) -> f32 {\n";
+        forest.write_wgsl(&mut code, voting, &feature_exprs).unwrap();
+        code += "return class_1_score;\n}\n";
+        crate::wgsl::validate_wgsl(&code).unwrap();
+
+        let has_fractional_vote = code.contains("class_0_score += 0.25;") && code.contains("class_1_score += 0.75;");
+        assert_eq!(has_fractional_vote, voting == VotingMode::Soft);
+    }
+}
+
+#[cfg(test)]
+const THREE_CLASS_TREE_DOTS: [&str; 2] = [
+    r#"
+    digraph Tree {
+        0 [label="node #0\nx[0] <= 100.0\nsamples = 30\nvalue = [10, 10, 10]\nclass = 0"] ;
+        1 [label="node #1\nsamples = 10\nvalue = [8, 2, 0]\nclass = 0"] ;
+        0 -> 1 [labeldistance=2.5, labelangle=45, headlabel="True"] ;
+        2 [label="node #2\nx[4] <= 50.0\nsamples = 20\nvalue = [2, 8, 10]\nclass = 2"] ;
+        0 -> 2 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;
+        3 [label="node #3\nsamples = 10\nvalue = [2, 8, 0]\nclass = 1"] ;
+        2 -> 3 ;
+        4 [label="node #4\nsamples = 10\nvalue = [0, 0, 10]\nclass = 2"] ;
+        2 -> 4 ;
+    }
+    "#,
+    r#"
+    digraph Tree {
+        0 [label="node #0\nx[2] <= 150.0\nsamples = 30\nvalue = [10, 10, 10]\nclass = 0"] ;
+        1 [label="node #1\nsamples = 15\nvalue = [0, 5, 5]\nclass = 1"] ;
+        0 -> 1 [labeldistance=2.5, labelangle=45, headlabel="True"] ;
+        2 [label="node #2\nsamples = 15\nvalue = [10, 4, 6]\nclass = 0"] ;
+        0 -> 2 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;
+    }
+    "#,
+];
+
+#[cfg(test)]
+pub fn three_class_forest() -> RandomForest{
+    RandomForest::from_trees(
+        THREE_CLASS_TREE_DOTS.iter().map(|dot| DecisionTree::parse(dot).unwrap()).collect()
+    ).unwrap()
+}
+
+#[test]
+fn test_cpu_evaluation(){
+    let forest = three_class_forest();
+    let low_x0 = [10.0, 0.0, 200.0, 0.0, 0.0];
+    let proba = forest.predict_proba(&low_x0);
+    // tree 0 says [0.8, 0.2, 0.0], tree 1 says [0.5, 0.2, 0.3]
+    assert!(proba.iter().zip([0.65, 0.2, 0.15]).all(|(a, b)| (a - b).abs() < 1e-6), "{proba:?}");
+    assert_eq!(forest.predict(&low_x0), 0);
+
+    // tree 0 says [0.0, 0.0, 1.0], tree 1 says [0.0, 0.5, 0.5]
+    let high_x4 = [101.0, 0.0, 0.0, 0.0, 60.0];
+    assert_eq!(forest.predict(&high_x4), 2);
+    // ... but the hard vote is a tie between classes 1 and 2, which goes to the lowest index
+    let hard_scores = forest.class_scores(&high_x4, VotingMode::Hard);
+    assert_eq!(hard_scores, vec![0.0, 0.5, 0.5]);
+    assert_eq!(argmax(&hard_scores), 1);
+}
+
+#[test]
+fn test_flat_forest_matches_tree_evaluation(){
+    let forest = three_class_forest();
+    let flat = forest.flatten();
+    assert_eq!(flat.roots, vec![0, 5]);
+    assert_eq!(flat.nodes.len(), 8);
+    assert_eq!(flat.to_words().len(), 2 + 2 + 8 * 4 + 5 * 3);
+
+    for features in [[10.0, 0.0, 200.0, 0.0, 0.0], [101.0, 0.0, 0.0, 0.0, 60.0], [101.0, 0.0, 160.0, 0.0, 10.0]]{
+        for voting in [VotingMode::Hard, VotingMode::Soft]{
+            assert_eq!(flat.class_scores(&features, voting), forest.class_scores(&features, voting));
+        }
+    }
+}
+
+#[cfg(test)]
+fn checkerboard_training_set(num_samples: usize) -> (Vec<f32>, Vec<usize>){
+    // class is decided by which side of 50.0 the first two features fall on. The third one is noise
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut features = vec![];
+    let mut labels = vec![];
+    for _ in 0..num_samples{
+        let sample: [f32; 3] = [rng.random_range(0.0..100.0), rng.random_range(0.0..100.0), rng.random_range(0.0..100.0)];
+        labels.push(usize::from(sample[0] > 50.0) + 2 * usize::from(sample[1] > 50.0));
+        features.extend(sample);
+    }
+    (features, labels)
+}
+
+#[test]
+fn test_training(){
+    let (features, labels) = checkerboard_training_set(400);
+    let params = TrainingParams{num_trees: 10, max_features: Some(2), seed: 1337, ..Default::default()};
+    let forest = RandomForest::train(&features, &labels, &params).unwrap();
+    assert_eq!(forest.num_classes(), 4);
+    let num_correct = features.chunks(3).zip(&labels)
+        .filter(|(sample, label)| forest.predict(sample) == **label)
+        .count();
+    assert!(num_correct >= 396, "only {num_correct} correct");
+
+    let (test_features, test_labels) = checkerboard_training_set(100);
+    let num_correct = test_features.chunks(3).zip(&test_labels)
+        .filter(|(sample, label)| forest.predict(sample) == **label)
+        .count();
+    assert!(num_correct >= 90, "only {num_correct} correct");
+
+    // same seed, same forest
+    let retrained = RandomForest::train(&features, &labels, &params).unwrap();
+    assert_eq!(forest.flatten().to_words(), retrained.flatten().to_words());
+}
+
+#[test]
+fn test_training_limits(){
+    fn depth(node: &TreeNode) -> usize{
+        match node{
+            TreeNode::Prediction(_) => 0,
+            TreeNode::Decision { le_child, gt_child, .. } => 1 + depth(le_child).max(depth(gt_child)),
+        }
+    }
+    let (features, labels) = checkerboard_training_set(200);
+
+    let params = TrainingParams{num_trees: 5, max_depth: Some(1), ..Default::default()};
+    let forest = RandomForest::train(&features, &labels, &params).unwrap();
+    assert!(forest.trees.iter().all(|tree| depth(&tree.root) <= 1));
+
+    // with as many samples per leaf as there are samples, nothing can be split
+    let params = TrainingParams{num_trees: 5, min_samples_leaf: 200, bootstrap: false, ..Default::default()};
+    let forest = RandomForest::train(&features, &labels, &params).unwrap();
+    assert!(forest.trees.iter().all(|tree| depth(&tree.root) == 0));
+    assert_eq!(forest.num_classes(), 4);
+
+    assert!(RandomForest::train(&[], &[0], &TrainingParams::default()).is_err());
+    assert!(RandomForest::train(&[], &[], &TrainingParams::default()).is_err());
+}
+
+#[test]
+fn test_training_on_npy(){
+    let features = NpyArray::<f32>::load("bench/features.npy").unwrap();
+    let labels = NpyArray::<u8>::load("bench/labels.npy").unwrap();
+    let params = TrainingParams{num_trees: 10, ..Default::default()};
+    let (forest, classes) = RandomForest::train_npy(&features, &labels, &params).unwrap();
+    assert_eq!(classes, vec![1, 2, 3]);
+    assert_eq!(forest.num_classes(), 3);
+    assert_eq!(forest.class_name(2), "3");
+
+    let num_features = features.shape()[1];
+    let num_correct = features.data().chunks(num_features).zip(labels.data())
+        .filter(|(sample, label)| classes[forest.predict(sample)] == **label)
+        .count();
+    assert!(num_correct as f32 > 0.95 * labels.data().len() as f32);
+
+    // predictions survive a trip through a file
+    let path = std::env::temp_dir().join(format!("gpu_filters_probabilities_{}.npy", std::process::id()));
+    forest.predict_proba_npy(&features).unwrap().save(&path).unwrap();
+    let probabilities = NpyArray::<f32>::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(probabilities.shape(), &[labels.data().len(), 3]);
+    let predictions = forest.predict_npy(&features).unwrap();
+    for ((sample, expected), class_idx) in features.data().chunks(num_features).zip(probabilities.data().chunks(3)).zip(predictions.data()){
+        assert_eq!(forest.predict_proba(sample), expected);
+        assert_eq!(forest.predict(sample), *class_idx as usize);
+    }
+    let too_few_features = NpyArray::new(vec![1, 1], vec![0.0]).unwrap();
+    assert!(forest.predict_proba_npy(&too_few_features).is_err());
+
+    let flat_labels = NpyArray::new(vec![2], vec![1u8, 2]).unwrap();
+    assert!(RandomForest::train_npy(&features, &flat_labels, &params).is_err());
+}
+
+#[test]
+fn test_dot_export_round_trip(){
+    let tree = DecisionTree::parse(TWO_LEVEL_TREE_DOT).unwrap();
+    let dot = tree.to_dot();
+    assert!(dot.contains(r#"0 [label="node #0\nx[4] <= 33.381138\nsamples = 88\nvalue = [68, 71]\nclass = 1"] ;"#));
+    assert!(dot.contains(r#"0 -> 4 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;"#));
+    assert_eq!(DecisionTree::parse(&dot).unwrap().to_dot(), dot);
+
+    // without training statistics, leaves show their distributions
+    let forest = RandomForest::from_flat(&three_class_forest().flatten()).unwrap();
+    let reparsed = RandomForest::from_trees(
+        forest.trees.iter().map(|tree| DecisionTree::parse(&tree.to_dot()).unwrap()).collect()
+    ).unwrap();
+    assert_eq!(reparsed.flatten(), forest.flatten());
+
+    let (features, labels) = checkerboard_training_set(100);
+    let params = TrainingParams{num_trees: 3, ..Default::default()};
+    let trained = RandomForest::train(&features, &labels, &params).unwrap();
+    let dir = std::env::temp_dir().join(format!("test_dot_export_round_trip_{}", std::process::id()));
+    trained.export_dir(dir.to_str().unwrap()).unwrap();
+    let mut exported: Vec<String> = std::fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    exported.sort();
+    assert_eq!(exported, vec!["tree_0.txt", "tree_1.txt", "tree_2.txt"]);
+    for (tree_idx, tree) in trained.trees.iter().enumerate(){
+        let dot = std::fs::read_to_string(dir.join(format!("tree_{tree_idx}.txt"))).unwrap();
+        assert_eq!(DecisionTree::parse(&dot).unwrap().to_dot(), tree.to_dot());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // quotes and backslashes in class names are escaped in the label and come back as they were
+    let names = ["say \"hi\"", "back\\slash", "not\\na line break", "plain"];
+    let named = trained.with_class_table(
+        names.iter().enumerate().map(|(index, name)| ClassInfo{index, name: (*name).to_owned(), color: None}).collect()
+    ).unwrap();
+    for tree in &named.trees{
+        let dot = tree.to_dot_with_class_names(&|class_idx| named.class_name(class_idx).to_owned());
+        let reparsed = DecisionTree::parse(&dot).unwrap();
+        assert!(!reparsed.class_names.is_empty());
+        for (class_idx, name) in &reparsed.class_names{
+            assert_eq!(name, names[*class_idx]);
+        }
+        assert_eq!(reparsed.to_dot(), dot);
+    }
+}
+
+#[test]
+fn test_decision_tree_parsing_errors(){
+    fn parse_body(body: &str) -> Result<DecisionTree, TreeError>{
+        DecisionTree::parse(&format!("digraph Tree {{\n{body}\n}}"))
+    }
+    const SPLIT: &str = r#"[label="x[0] <= 0.5\nsamples = 2\nvalue = [1, 1]\nclass = 0"]"#;
+    const LEAF: &str = r#"[label="samples = 1\nvalue = [1, 0]\nclass = 0"]"#;
+
+    let single_leaf = parse_body(&format!("0 {LEAF} ;")).unwrap();
+    assert_eq!(single_leaf.highest_feature_idx(), None);
+    assert_eq!(single_leaf.highest_class_idx(), 1);
+
+    let err = parse_body(&format!("0 {SPLIT} ; 1 {LEAF} ; 1 {LEAF} ; 2 {LEAF} ; 0 -> 1 ; 0 -> 2 ;"));
+    assert!(matches!(err, Err(TreeError::DuplicateNode(1))));
+    let err = parse_body(&format!("0 {SPLIT} ; 1 {LEAF} ; 0 -> 1 ; 0 -> 2 ;"));
+    assert!(matches!(err, Err(TreeError::DanglingEdge{origin: 0, target: 2})));
+    let err = parse_body(&format!("0 {SPLIT} ; 1 {LEAF} ; 2 {LEAF} ; 3 {LEAF} ; 0 -> 1 ; 0 -> 2 ;"));
+    assert!(matches!(err, Err(TreeError::UnreachableNode(3))));
+    let err = parse_body(&format!("0 {SPLIT} ; 1 {SPLIT} ; 2 {LEAF} ; 0 -> 1 ; 0 -> 2 ; 1 -> 0 ; 1 -> 2 ;"));
+    assert!(matches!(err, Err(TreeError::Cycle(0))));
+    let err = parse_body(&format!("0 {SPLIT} ; 1 {LEAF} ; 0 -> 1 ;"));
+    assert!(matches!(err, Err(TreeError::WrongChildCount{node_id: 0, num_children: 1})));
+    let err = parse_body(&format!("0 {SPLIT} ;"));
+    assert!(matches!(err, Err(TreeError::WrongChildCount{node_id: 0, num_children: 0})));
+    let err = parse_body(&format!("1 {LEAF} ;"));
+    assert!(matches!(err, Err(TreeError::MissingRoot)));
+    let three_class_leaf = r#"[label="value = [0, 0, 1]\nclass = 2"]"#;
+    let err = parse_body(&format!("0 {SPLIT} ; 1 {LEAF} ; 2 {three_class_leaf} ; 0 -> 1 ; 0 -> 2 ;"));
+    assert!(matches!(err, Err(TreeError::ClassCountMismatch{expected: 2, found: 3, ..})));
+
+    let err = RandomForest::from_trees(vec![
+        DecisionTree::parse(TWO_LEVEL_TREE_DOT).unwrap(),
+        parse_body(&format!("0 {three_class_leaf} ;")).unwrap(),
+    ]);
+    assert!(matches!(err, Err(ForestError::ClassCountMismatch{tree_idx: 1, expected: 2, found: 3})));
+    assert!(matches!(RandomForest::from_trees(vec![]), Err(ForestError::NoTrees)));
+}
+
+#[test]
+fn test_class_names(){
+    const NAMED_TREE_DOT: &str = r#"
+        digraph Tree {
+            0 [label="node #0\nx[1] <= 0.5\nsamples = 10\nvalue = [4, 6]\nclass = membrane"] ;
+            1 [label="node #1\nsamples = 4\nvalue = [4, 0]\nclass = background"] ;
+            0 -> 1 [labeldistance=2.5, labelangle=45, headlabel="True"] ;
+            2 [label="node #2\nsamples = 6\nvalue = [0, 6]\nclass = membrane"] ;
+            0 -> 2 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;
+        }
+    "#;
+    let tree = DecisionTree::parse(NAMED_TREE_DOT).unwrap();
+    // the class index comes from the value, the class line only names it
+    assert_eq!(tree.root.leaf_for(&[0.0, 0.0]).class, 0);
+    let forest = RandomForest::from_trees(vec![tree]).unwrap();
+    let names: Vec<&str> = forest.classes().iter().map(|class| class.name.as_str()).collect();
+    assert_eq!(names, vec!["background", "membrane"]);
+    assert_eq!(forest.predict(&[0.0, 1.0]), 1);
+
+    let dot = forest.trees[0].to_dot();
+    assert!(dot.contains(r#"value = [0, 6]\nclass = membrane"#));
+    assert_eq!(DecisionTree::parse(&dot).unwrap().class_names, forest.trees[0].class_names);
+
+    // trees only know the names of classes that show up in them; the forest falls back to indices
+    let partial = DecisionTree::parse(r#"digraph Tree { 0 [label="value = [0, 1, 0]\nclass = membrane"] ; }"#).unwrap();
+    let forest = RandomForest::from_trees(vec![partial]).unwrap();
+    assert_eq!(forest.class_name(0), "0");
+    assert_eq!(forest.class_name(1), "membrane");
+
+    let renamed = NAMED_TREE_DOT.replace("value = [4, 0]\\nclass = background", "value = [4, 0]\\nclass = cytoplasm");
+    let err = RandomForest::from_trees(vec![
+        DecisionTree::parse(NAMED_TREE_DOT).unwrap(), DecisionTree::parse(&renamed).unwrap()
+    ]);
+    assert!(matches!(err, Err(ForestError::ClassNameConflict{tree_idx: 1, class_idx: 0, ..})));
+    let conflicting = NAMED_TREE_DOT.replace("value = [0, 6]\\nclass = membrane", "value = [0, 6]\\nclass = nucleus");
+    assert!(matches!(DecisionTree::parse(&conflicting), Err(TreeError::ClassNameConflict{class_idx: 1, ..})));
+    // without a value there is nothing to take the class index from
+    let nameless = DecisionTree::parse(r#"digraph Tree { 0 [label="class = membrane"] ; }"#);
+    assert!(matches!(nameless, Err(TreeError::BadLabel{node_id: 0, ..})));
+
+    // the partial tree has three classes, so a table for two doesn't fit
+    let colored = forest.with_class_table(vec![
+        ClassInfo::unnamed(0), ClassInfo{index: 1, name: "membrane".to_owned(), color: Some([255, 0, 0, 255])}
+    ]);
+    assert!(matches!(colored, Err(ForestError::BadClassTable(_))));
+}
+
+#[test]
+fn test_uncertainty(){
+    assert_eq!(Uncertainty::Margin.of(&[0.0, 1.0, 0.0]), 0.0);
+    assert_eq!(Uncertainty::Entropy.of(&[0.0, 1.0, 0.0]), 0.0);
+    assert!((Uncertainty::Margin.of(&[0.2, 0.5, 0.3]) - 0.8).abs() < 1e-6);
+    assert_eq!(Uncertainty::Margin.of(&[0.5, 0.0, 0.5]), 1.0);
+    assert!((Uncertainty::Entropy.of(&[0.25; 4]) - 1.0).abs() < 1e-6);
+    assert!((Uncertainty::Entropy.of(&[0.5, 0.5, 0.0, 0.0]) - 0.5).abs() < 1e-6);
+    assert_eq!(Uncertainty::Entropy.of(&[1.0]), 0.0);
+
+    let mut wgsl = String::new();
+    for uncertainty in [Uncertainty::Margin, Uncertainty::Entropy]{
+        wgsl.clear();
+        wgsl += "fn uncertainty_of(class_0_score: f32, class_1_score: f32, class_2_score: f32) -> f32 {";
+        uncertainty.write_wgsl(&mut wgsl, 3).unwrap();
+        wgsl += "\n    return uncertainty;\n}";
+        crate::wgsl::validate_wgsl(&wgsl).unwrap();
+    }
+}
+
+#[test]
+fn test_from_dir_orders_trees_numerically(){
+    let dir = std::env::temp_dir().join(format!("test_from_dir_orders_trees_numerically_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    // each tree is a single leaf voting for its position in the expected order
+    let file_names = ["tree_10.txt", "tree_2.txt", "tree_3_7.txt", "tree_3_10.txt", "tree_1.txt"];
+    let positions = [4, 1, 2, 3, 0];
+    for (file_name, position) in file_names.iter().zip(positions){
+        let one_hot: Vec<&str> = (0..5).map(|class| if class == position { "1" } else { "0" }).collect();
+        let dot = format!(r#"digraph Tree {{ 0 [label="value = [{}]\nclass = {position}"] ; }}"#, one_hot.join(", "));
+        std::fs::write(dir.join(file_name), dot).unwrap();
+    }
+    std::fs::write(dir.join("forest.json"), "{}").unwrap();
+
+    let forest = RandomForest::from_dir(dir.to_str().unwrap()).unwrap();
+    let classes: Vec<usize> = forest.trees.iter().map(|tree| leaf_class(&tree.root)).collect();
+    assert_eq!(classes, vec![0, 1, 2, 3, 4]);
+
+    std::fs::write(dir.join("tree_02.txt"), "").unwrap();
+    assert!(matches!(RandomForest::from_dir(dir.to_str().unwrap()), Err(ForestError::DuplicateTreeIndex(..))));
+    std::fs::remove_file(dir.join("tree_02.txt")).unwrap();
+
+    std::fs::write(dir.join("tree_final.txt"), "").unwrap();
+    assert!(matches!(RandomForest::from_dir(dir.to_str().unwrap()), Err(ForestError::BadFileName(..))));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_used_features(){
+    assert_eq!(three_class_forest().used_features(), BTreeSet::from([0, 2, 4]));
+    let single_leaf = DecisionTree::parse(r#"digraph Tree { 0 [label="value = [1, 0]\nclass = 0"] ; }"#).unwrap();
+    assert!(RandomForest::from_trees(vec![single_leaf]).unwrap().used_features().is_empty());
+}
+
+#[test]
+fn test_feature_spec_must_cover_forest(){
+    use crate::feature_spec::FilterKind;
+
+    // the forest splits on features 0, 2 and 4
+    let small_spec = FeatureSpec::from_filters(&[(FilterKind::GaussianSmoothing, 1.0)], 3);
+    let err = three_class_forest().with_feature_spec(small_spec);
+    assert!(matches!(err, Err(ForestError::UnknownFeature{feature_idx: 4, num_features: 3})));
+
+    let spec = FeatureSpec::from_filters(&[(FilterKind::GaussianSmoothing, 1.0), (FilterKind::GaussianSmoothing, 2.0)], 3);
+    let forest = three_class_forest().with_feature_spec(spec.clone()).unwrap();
+    assert_eq!(forest.feature_spec(), Some(&spec));
+}
@@ -1,7 +1,5 @@
 use std::{marker::PhantomData, time::Instant};
 
-use crate::util::copy_bytes;
-
 /// A buffer that can be mapped to CPU and read back
 #[derive(Clone)]
 pub struct DownloadBuffer<T>{
@@ -15,20 +13,6 @@ pub struct DownloadGuard<T>{
     needs_unmapping: bool,
 }
 
-impl DownloadBuffer<[f32; 4]>{
-    pub fn new_for_predictions(
-        input: &image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
-        device: &wgpu::Device,
-        label: Option<&str>
-    ) -> Self{
-        Self::new(
-            device,
-            label,
-            (input.width() * input.height()) as usize,
-        )
-    }
-}
-
 impl<T: bytemuck::AnyBitPattern> DownloadBuffer<T>{
     pub fn new(device: &wgpu::Device, label: Option<&str>, count: usize) -> Self{
         let buffer = device.create_buffer(&wgpu::BufferDescriptor {
@@ -65,12 +49,15 @@ impl<T: bytemuck::AnyBitPattern> DownloadBuffer<T>{
 }
 
 impl<T: bytemuck::AnyBitPattern> DownloadGuard<T>{
-    pub fn readback(mut self) -> (Vec<T>, DownloadBuffer<T>){
+    /// Waits for the mapping and lets `decode` read the values straight out of the mapped memory,
+    /// so that turning them into something else doesn't need an intermediate copy
+    pub fn read_mapped<R>(mut self, decode: impl FnOnce(&[T]) -> R) -> (R, DownloadBuffer<T>){
         self.waiter.recv().unwrap(); //wait for map async to be done
-        let out: Vec<T> = {
+        let out = {
             let read_buffer_slice = self.dl_buffer.buffer.slice(..);
             let read_buffer_view = read_buffer_slice.get_mapped_range();
-            copy_bytes(&read_buffer_view, "from GPU to cpu")
+            // mapped ranges are aligned to `wgpu::MAP_ALIGNMENT`, so this cast doesn't depend on the allocator
+            decode(bytemuck::cast_slice(&read_buffer_view))
         };
         self.dl_buffer.buffer.unmap();
         self.needs_unmapping = false;
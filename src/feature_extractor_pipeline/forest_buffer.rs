@@ -0,0 +1,126 @@
+use std::fmt::Display;
+
+use crate::decision_tree::{FlatForest, FlatNode, RandomForest, VotingMode};
+use crate::util::{Binding, Group};
+
+/// A storage buffer holding a [`FlatForest`], which the shader walks with a fixed traversal loop.
+///
+/// Unlike `RandomForest::write_wgsl`, the generated code doesn't depend on the trees themselves,
+/// only on the number of classes, so swapping forests is just a matter of uploading a new buffer
+pub struct ForestBufferSlot {
+    name: String,
+    group: Group,
+    binding: Binding,
+    num_classes: usize,
+}
+
+impl ForestBufferSlot {
+    pub fn new(name: String, group: Group, binding: Binding, num_classes: usize) -> Self {
+        Self{name, group, binding, num_classes}
+    }
+    pub fn num_classes(&self) -> usize {
+        self.num_classes
+    }
+    pub fn create_buffer(&self, device: &wgpu::Device, forest: &RandomForest) -> wgpu::Buffer {
+        let words = forest.flatten().to_words();
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&format!("forest_buffer__{}", self.name)),
+            mapped_at_creation: true,
+            size: (words.len() * size_of::<u32>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        {
+            let mut bytes_slice = buffer.slice(..).get_mapped_range_mut();
+            let buffer_words: &mut [u32] = bytemuck::cast_slice_mut(&mut bytes_slice);
+            buffer_words.copy_from_slice(&words);
+        }
+        buffer.unmap();
+        buffer
+    }
+    /// Writes the loop that walks every tree in the buffer for the current pixel. Like
+    /// `RandomForest::write_wgsl`, it leaves the result in `class_<k>_score` variables.
+    ///
+    /// Expects the pixel's features to be in a `features: array<f32, N>` variable
+    pub fn write_wgsl_traversal(&self, out: &mut impl std::fmt::Write, voting: VotingMode) -> Result<(), std::fmt::Error> {
+        let name = &self.name;
+        let num_classes = self.num_classes;
+        let header_len = FlatForest::HEADER_LEN;
+        let node_len = FlatForest::NODE_LEN;
+        let leaf = FlatNode::LEAF;
+        let accumulate_leaf = match voting {
+            VotingMode::Hard => format!("
+                        class_scores[{name}[node_offset + 3u]] += 1.0;"
+            ),
+            VotingMode::Soft => format!("
+                        let probabilities_offset = leaf_probabilities_start + {name}[node_offset + 2u];
+                        for (var class_idx = 0u; class_idx < {num_classes}u; class_idx++){{
+                            class_scores[class_idx] += bitcast<f32>({name}[probabilities_offset + class_idx]);
+                        }}"
+            ),
+        };
+        write!(out, "
+                var class_scores = array<f32, {num_classes}>();
+                let num_trees = {name}[0];
+                let num_nodes = {name}[1];
+                let nodes_start = {header_len}u + num_trees;
+                let leaf_probabilities_start = nodes_start + num_nodes * {node_len}u;
+                for (var tree_idx = 0u; tree_idx < num_trees; tree_idx++){{
+                    var node_offset = nodes_start + {name}[{header_len}u + tree_idx] * {node_len}u;
+                    // bounded by num_nodes so that a malformed buffer can't hang the GPU
+                    for (var depth = 0u; depth < num_nodes; depth++){{
+                        let feature_idx = {name}[node_offset];
+                        if feature_idx == {leaf}u {{
+                            break;
+                        }}
+                        var next_node: u32;
+                        if features[feature_idx] <= bitcast<f32>({name}[node_offset + 1u]) {{
+                            next_node = {name}[node_offset + 2u];
+                        }} else {{
+                            next_node = {name}[node_offset + 3u];
+                        }}
+                        node_offset = nodes_start + next_node * {node_len}u;
+                    }}
+                    {accumulate_leaf}
+                }}
+        ")?;
+        for class_idx in 0..num_classes{
+            write!(out, "
+                let class_{class_idx}_score = class_scores[{class_idx}] / f32(num_trees);"
+            )?;
+        }
+        Ok(())
+    }
+    pub fn to_binding_type(&self) -> wgpu::BindingType {
+        wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        }
+    }
+    pub fn to_bind_group_layout_entry(&self) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding: self.binding.into(),
+            count: None,
+            ty: self.to_binding_type(),
+            visibility: wgpu::ShaderStages::COMPUTE,
+        }
+    }
+    pub fn to_bind_group_entry<'a>(&self, buffer: &'a wgpu::Buffer) -> wgpu::BindGroupEntry<'a> {
+        wgpu::BindGroupEntry{
+            binding: self.binding.into(),
+            resource: buffer.as_entire_binding(),
+        }
+    }
+}
+
+impl Display for ForestBufferSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = &self.name;
+        let group = &self.group;
+        let binding = &self.binding;
+        write!(
+            f,
+            "@group({group}) @binding({binding}) var<storage, read> {name} : array<u32>;",
+        )
+    }
+}
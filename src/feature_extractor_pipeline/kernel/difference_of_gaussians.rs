@@ -0,0 +1,76 @@
+use crate::feature_spec::FilterKind;
+
+use super::gaussian_blur::GaussianBlur;
+use super::{Filter, SeparableKernel};
+
+/// How much smaller ilastik makes the second sigma of its Difference of Gaussians
+pub const INNER_SIGMA_RATIO: f32 = 0.66;
+
+/// The image smoothed with a Gaussian of `sigma` minus the image smoothed with a Gaussian of
+/// `inner_sigma`, like ilastik's Difference of Gaussians. Its kernels are exactly those of the
+/// [`GaussianBlur`]s at the same sigmas, so the pipeline shares their responses with any such
+/// smoothing features instead of convolving twice
+#[derive(Clone)]
+pub struct DifferenceOfGaussians{
+    /// The scale that [`Feature::sigma`](crate::feature_spec::Feature::sigma) refers to
+    pub sigma: f32,
+    /// The scale that [`Feature::inner_sigma`](crate::feature_spec::Feature::inner_sigma) refers to
+    pub inner_sigma: f32,
+}
+
+impl DifferenceOfGaussians{
+    /// The sigmas ilastik uses: `sigma` and `INNER_SIGMA_RATIO * sigma`
+    pub fn new(sigma: f32) -> Self{
+        Self::with_sigmas(sigma, INNER_SIGMA_RATIO * sigma)
+    }
+    pub fn with_sigmas(sigma: f32, inner_sigma: f32) -> Self{
+        Self{sigma, inner_sigma}
+    }
+}
+
+impl Filter for DifferenceOfGaussians{
+    fn kind(&self) -> FilterKind{
+        FilterKind::DifferenceOfGaussians
+    }
+    fn sigma(&self) -> f32{
+        self.sigma
+    }
+    fn inner_sigma(&self) -> Option<f32>{
+        Some(self.inner_sigma)
+    }
+    /// The Gaussian of `sigma`, then the one of `inner_sigma`
+    fn kernels(&self) -> Vec<SeparableKernel>{
+        [self.sigma, self.inner_sigma].into_iter()
+            .flat_map(|sigma| GaussianBlur::new(sigma).kernels())
+            .collect()
+    }
+    fn components_from_responses(&self, responses: &[f32]) -> Vec<f32>{
+        vec![responses[0] - responses[1]]
+    }
+    fn write_wgsl_components(
+        &self, out: &mut dyn std::fmt::Write, responses: &[String], components: &[String]
+    ) -> std::fmt::Result{
+        write!(out, "
+                let {} = {} - {};", components[0], responses[0], responses[1])
+    }
+    fn clone_box(&self) -> Box<dyn Filter>{
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_difference_of_gaussians(){
+    use super::apply_filter_on_cpu;
+
+    let image = image::RgbaImage::from_fn(12, 9, |x, y| image::Rgba([(x * 19 + y * y * 7) as u8, 80, 0, 255]));
+    let filter = DifferenceOfGaussians::new(2.0);
+    assert_eq!(filter.inner_sigma, 1.32);
+    let outer = &apply_filter_on_cpu(&GaussianBlur::new(2.0), &image, 0)[0];
+    let inner = &apply_filter_on_cpu(&GaussianBlur::new(1.32), &image, 0)[0];
+    let difference = &apply_filter_on_cpu(&filter, &image, 0)[0];
+    for ((difference, outer), inner) in difference.iter().zip(outer).zip(inner){
+        assert_eq!(*difference, outer - inner);
+    }
+    // both Gaussians keep a constant image as it is
+    assert!(apply_filter_on_cpu(&filter, &image, 1)[0].iter().all(|value| value.abs() < 1e-3));
+}
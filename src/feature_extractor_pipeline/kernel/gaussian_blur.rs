@@ -1,54 +1,166 @@
 use nalgebra::Vector2;
 
+use crate::feature_spec::FilterKind;
+
+use super::{Filter, SeparableKernel};
+
+/// How many sigmas a kernel reaches out from its center, like fastfilters' `window_size`
+pub const WINDOW_SIZE: f32 = 3.5;
+
+/// The radius vigra and fastfilters give the kernel of the `order`th derivative of a Gaussian:
+/// `(int)(WINDOW_SIZE * sigma + 0.5 * order + 0.5)`, but at least 1
+pub fn gaussian_radius(sigma: f32, order: u32) -> usize{
+    ((WINDOW_SIZE * sigma + 0.5 * order as f32 + 0.5) as usize).max(1)
+}
+
+/// The weights of the `order`th derivative of a Gaussian for the offsets `-radius..=radius`, as
+/// vigra's `initGaussianDerivative` makes them, so that features match what ilastik computes:
+/// - a smoothing kernel is scaled to sum to exactly 1 over the truncated window;
+/// - a derivative kernel first has its mean subtracted, so that it doesn't respond to a constant image,
+///   and is then scaled so that `Σ x^order * w[x] / order! == 1`, i.e. so that it gives exactly the
+///   `order`th derivative of `x^order / order!`.
+///
+/// The weights are for correlation: the response at `i` is `Σ input[i + x] * w[x]`
+pub fn gaussian_kernel_1d(sigma: f32, order: u32, radius: usize) -> Vec<f32>{
+    let sigma = f64::from(sigma);
+    let iradius = radius as i64;
+    let mut weights: Vec<f64> = (-iradius..=iradius)
+        .map(|x| {
+            let x = x as f64;
+            let gauss = (-x * x / (2.0 * sigma * sigma)).exp();
+            match order {
+                0 => gauss,
+                // the derivative of the Gaussian at -x, since these weights are correlated with the input
+                1 => x / (sigma * sigma) * gauss,
+                2 => (x * x / sigma.powi(4) - 1.0 / (sigma * sigma)) * gauss,
+                _ => panic!("Gaussian derivatives of order {order} are not supported"),
+            }
+        })
+        .collect();
+    if order > 0{
+        let dc = weights.iter().sum::<f64>() / weights.len() as f64;
+        weights.iter_mut().for_each(|weight| *weight -= dc);
+    }
+    let faculty: f64 = (2..=order).map(f64::from).product();
+    let moment: f64 = (-iradius..=iradius).zip(&weights)
+        .map(|(x, weight)| weight * (x as f64).powi(order as i32) / faculty)
+        .sum();
+    weights.into_iter().map(|weight| (weight / moment) as f32).collect()
+}
+
+/// Maps `idx` into `0..len` by mirroring at the borders without repeating the border pixel
+/// (`-1 -> 1`, `len -> len - 2`), like vigra's `BORDER_TREATMENT_REFLECT`
+pub fn reflect_index(idx: i64, len: i64) -> i64{
+    if len == 1{
+        return 0
+    }
+    let period = 2 * (len - 1);
+    let idx = idx.rem_euclid(period);
+    if idx < len { idx } else { period - idx }
+}
+
 #[derive(Clone)]
-pub struct GaussianBlur<const KSIDE: usize>{
-    pub sigma: f32,
+pub struct GaussianBlur{
+    sigma: f32,
+    /// [`Self::weights_1d`], computed once since the CPU references look them up for every tap
+    weights: Vec<f32>,
 }
 
-impl<const KSIDE: usize> GaussianBlur<KSIDE> {
+impl GaussianBlur {
     pub fn new(sigma: f32) -> Self{
         Self{
             sigma,
+            weights: gaussian_kernel_1d(sigma, 0, gaussian_radius(sigma, 0)),
         }
     }
-    pub const fn kernel_side_len(&self) -> usize{
-        KSIDE
+    pub fn sigma(&self) -> f32{
+        self.sigma
+    }
+    pub fn kernel_side_len(&self) -> usize{
+        self.radius() * 2 + 1
     }
     pub fn radius(&self) -> usize{
-        (KSIDE - 1) / 2
+        gaussian_radius(self.sigma, 0)
     }
-    pub fn required_size_in_bytes(&self) -> usize{
-        self.kernel_side_len().pow(2) * std::mem::size_of::<f32>()
+    /// The 1D kernel that the 2D kernel is the outer product of, for offsets `-radius..=radius`
+    pub fn weights_1d(&self) -> &[f32]{
+        &self.weights
     }
     pub fn kernel_at(&self, center_offset: Vector2<i64>) -> f32 {
-        use std::f32::consts::{PI, E};
-        
-        let x_2 = (center_offset.x * center_offset.x) as f32;
-        let y_2 = (center_offset.y * center_offset.y) as f32;
-        let two_sigma_2 = 2f32 * self.sigma * self.sigma;
-        let exponent = -(x_2 + y_2) / two_sigma_2;
-
-        return (1f32 / (PI * two_sigma_2)) * E.powf(exponent);
-    }
-    pub fn wgsl_indexing_from_yx_offset(&self, offset_var: &str) -> String{
-        let radius = self.radius();
-        let y = format!("({offset_var}.y + {radius})");
-        let x = format!("({offset_var}.x + {radius})");
-        format!("[{y}][{x}]")
-    }
-    pub fn fill_slice_yx(&self, buffer: &mut [[f32; KSIDE]; KSIDE]){
-        let iradius: i64 = self.radius().try_into().unwrap();
-
-        // let mut total: f32 = 0.0;
-        for y in -iradius..=iradius{
-            for x in -iradius..=iradius{
-                let val = self.kernel_at(Vector2::new(x, y));
-                let fixed_y = usize::try_from(y + iradius).unwrap();
-                let fixed_x = usize::try_from(x + iradius).unwrap();
-                buffer[fixed_y][fixed_x] = val;
-                // total += val;
-            }
+        self.kernel_1d_at(center_offset.x) * self.kernel_1d_at(center_offset.y)
+    }
+    /// The weight of [`Self::weights_1d`] at `center_offset`, i.e.
+    /// `kernel_at((x, y)) == kernel_1d_at(x) * kernel_1d_at(y)`
+    pub fn kernel_1d_at(&self, center_offset: i64) -> f32 {
+        let radius = self.radius() as i64;
+        self.weights[usize::try_from(center_offset + radius).unwrap()]
+    }
+}
+
+impl Filter for GaussianBlur{
+    fn kind(&self) -> FilterKind{
+        FilterKind::GaussianSmoothing
+    }
+    fn sigma(&self) -> f32{
+        self.sigma
+    }
+    fn kernels(&self) -> Vec<SeparableKernel>{
+        vec![SeparableKernel{x: self.weights.clone(), y: self.weights.clone()}]
+    }
+    fn components_from_responses(&self, responses: &[f32]) -> Vec<f32>{
+        vec![responses[0]]
+    }
+    fn write_wgsl_components(
+        &self, out: &mut dyn std::fmt::Write, responses: &[String], components: &[String]
+    ) -> std::fmt::Result{
+        write!(out, "
+                let {} = {};", components[0], responses[0])
+    }
+    fn clone_box(&self) -> Box<dyn Filter>{
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_radius_follows_sigma(){
+    assert_eq!(GaussianBlur::new(0.1).radius(), 1);
+    assert_eq!(GaussianBlur::new(0.3).radius(), 1);
+    assert_eq!(GaussianBlur::new(0.7).radius(), 2);
+    assert_eq!(GaussianBlur::new(1.0).radius(), 4);
+    assert_eq!(GaussianBlur::new(10.0).radius(), 35);
+    assert_eq!(GaussianBlur::new(10.0).kernel_side_len(), 71);
+    assert_eq!(gaussian_radius(1.0, 1), 4);
+    assert_eq!(gaussian_radius(1.0, 2), 5);
+}
+
+#[test]
+fn test_kernel_normalization(){
+    for sigma in [0.3, 0.7, 1.0, 1.6, 3.5, 10.0]{
+        let kernel = GaussianBlur::new(sigma);
+        let sum: f32 = kernel.weights_1d().iter().sum();
+        assert!((sum - 1.0).abs() < 1e-6, "sigma {sigma} sums to {sum}");
+        let sum_2d: f32 = kernel.weights_1d().iter()
+            .flat_map(|weight_y| kernel.weights_1d().iter().map(move |weight_x| weight_y * weight_x))
+            .sum();
+        assert!((sum_2d - 1.0).abs() < 1e-5, "2D kernel of sigma {sigma} sums to {sum_2d}");
+
+        for order in [1, 2]{
+            let radius = gaussian_radius(sigma, order);
+            let weights = gaussian_kernel_1d(sigma, order, radius);
+            let dc: f32 = weights.iter().sum();
+            assert!(dc.abs() < 1e-5, "order {order} sigma {sigma} has DC {dc}");
+            // the derivative of x^order / order! is 1
+            let moment: f32 = (-(radius as i32)..=radius as i32).zip(&weights)
+                .map(|(x, weight)| (x as f32).powi(order as i32) * weight)
+                .sum();
+            let faculty = if order == 2 { 2.0 } else { 1.0 };
+            assert!((moment / faculty - 1.0).abs() < 1e-4, "order {order} sigma {sigma} gives {moment}");
         }
-        // assert!(1.0 - total < 0.001);
     }
+    // a ramp going up to the right has a positive first derivative
+    let weights = gaussian_kernel_1d(1.0, 1, 4);
+    assert!(weights[5..].iter().all(|weight| *weight > 0.0));
+
+    assert_eq!((-3..8).map(|idx| reflect_index(idx, 4)).collect::<Vec<_>>(), vec![3, 2, 1, 0, 1, 2, 3, 2, 1, 0, 1]);
+    assert_eq!(reflect_index(5, 1), 0);
 }
@@ -0,0 +1,69 @@
+use crate::feature_spec::FilterKind;
+
+use super::gaussian_blur::{gaussian_kernel_1d, gaussian_radius};
+use super::{Filter, SeparableKernel};
+
+/// The length of the gradient of the image smoothed with a Gaussian, like vigra's
+/// `gaussianGradientMagnitude`: each derivative is a first derivative of a Gaussian along its axis,
+/// smoothed with a Gaussian of the same sigma along the other one
+#[derive(Clone)]
+pub struct GaussianGradientMagnitude{
+    pub sigma: f32,
+}
+
+impl GaussianGradientMagnitude{
+    pub fn new(sigma: f32) -> Self{
+        Self{sigma}
+    }
+}
+
+impl Filter for GaussianGradientMagnitude{
+    fn kind(&self) -> FilterKind{
+        FilterKind::GaussianGradientMagnitude
+    }
+    fn sigma(&self) -> f32{
+        self.sigma
+    }
+    /// The derivative along x, then the one along y
+    fn kernels(&self) -> Vec<SeparableKernel>{
+        let smoothing = gaussian_kernel_1d(self.sigma, 0, gaussian_radius(self.sigma, 0));
+        let derivative = gaussian_kernel_1d(self.sigma, 1, gaussian_radius(self.sigma, 1));
+        vec![
+            SeparableKernel{x: derivative.clone(), y: smoothing.clone()},
+            SeparableKernel{x: smoothing, y: derivative},
+        ]
+    }
+    fn components_from_responses(&self, responses: &[f32]) -> Vec<f32>{
+        vec![responses[0].hypot(responses[1])]
+    }
+    fn write_wgsl_components(
+        &self, out: &mut dyn std::fmt::Write, responses: &[String], components: &[String]
+    ) -> std::fmt::Result{
+        let (dx, dy) = (&responses[0], &responses[1]);
+        write!(out, "
+                let {} = sqrt({dx} * {dx} + {dy} * {dy});", components[0])
+    }
+    fn clone_box(&self) -> Box<dyn Filter>{
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_gradient_magnitude_of_ramp(){
+    use super::apply_filter_on_cpu;
+
+    // away from the borders, the gradient of 3x + 4y is (3, 4) at every scale
+    let image = image::RgbaImage::from_fn(30, 30, |x, y| image::Rgba([(3 * x + 4 * y) as u8, 0, 0, 255]));
+    for sigma in [0.7, 1.0, 2.0]{
+        let filter = GaussianGradientMagnitude::new(sigma);
+        let magnitudes = &apply_filter_on_cpu(&filter, &image, 0)[0];
+        let margin = filter.radius() as u32;
+        for (y, x) in (margin..30 - margin).flat_map(|y| (margin..30 - margin).map(move |x| (y, x))){
+            let magnitude = magnitudes[(y * 30 + x) as usize];
+            assert!((magnitude - 5.0).abs() < 1e-3, "sigma {sigma} at ({x}, {y}) gives {magnitude}");
+        }
+        // a constant image has no gradient
+        let flat = &apply_filter_on_cpu(&filter, &image, 1)[0];
+        assert!(flat.iter().all(|magnitude| *magnitude == 0.0));
+    }
+}
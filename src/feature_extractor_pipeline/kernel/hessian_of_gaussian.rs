@@ -0,0 +1,117 @@
+use crate::feature_spec::FilterKind;
+
+use super::gaussian_blur::{gaussian_kernel_1d, gaussian_radius};
+use super::{Filter, SeparableKernel};
+
+/// The eigenvalues of the Hessian matrix of the image smoothed with a Gaussian, like ilastik's
+/// "Hessian of Gaussian Eigenvalues". The second derivatives come from vigra's `hessianOfGaussian`
+/// kernels, and the eigenvalues are sorted from largest to smallest, so component 0 is the largest
+/// eigenvalue and component 1 the smallest
+#[derive(Clone)]
+pub struct HessianOfGaussianEigenvalues{
+    pub sigma: f32,
+}
+
+impl HessianOfGaussianEigenvalues{
+    pub fn new(sigma: f32) -> Self{
+        Self{sigma}
+    }
+}
+
+/// The eigenvalues of the symmetric matrix `[[xx, xy], [xy, yy]]`, largest first
+pub fn eigenvalues_2d(xx: f32, xy: f32, yy: f32) -> [f32; 2]{
+    let mean = (xx + yy) / 2.0;
+    let radius = ((xx - yy) / 2.0).hypot(xy);
+    [mean + radius, mean - radius]
+}
+
+/// The eigenvalues of the symmetric matrix `[[xx, xy, xz], [xy, yy, yz], [xz, yz, zz]]`, largest
+/// first, with the trigonometric solution of its characteristic polynomial
+pub fn eigenvalues_3d(xx: f32, xy: f32, xz: f32, yy: f32, yz: f32, zz: f32) -> [f32; 3]{
+    let [xx, xy, xz, yy, yz, zz] = [xx, xy, xz, yy, yz, zz].map(f64::from);
+    let off_diagonal = xy * xy + xz * xz + yz * yz;
+    let mean = (xx + yy + zz) / 3.0;
+    let deviation = (((xx - mean).powi(2) + (yy - mean).powi(2) + (zz - mean).powi(2) + 2.0 * off_diagonal) / 6.0).sqrt();
+    if deviation == 0.0{
+        return [mean as f32; 3]
+    }
+    // the determinant of (matrix - mean * identity) / deviation, which is in -2..=2
+    let (bxx, byy, bzz) = ((xx - mean) / deviation, (yy - mean) / deviation, (zz - mean) / deviation);
+    let (bxy, bxz, byz) = (xy / deviation, xz / deviation, yz / deviation);
+    let determinant = bxx * (byy * bzz - byz * byz) - bxy * (bxy * bzz - byz * bxz) + bxz * (bxy * byz - byy * bxz);
+    let angle = (determinant / 2.0).clamp(-1.0, 1.0).acos() / 3.0;
+    let largest = mean + 2.0 * deviation * angle.cos();
+    let smallest = mean + 2.0 * deviation * (angle + 2.0 * std::f64::consts::PI / 3.0).cos();
+    [largest, 3.0 * mean - largest - smallest, smallest].map(|eigenvalue| eigenvalue as f32)
+}
+
+impl Filter for HessianOfGaussianEigenvalues{
+    fn kind(&self) -> FilterKind{
+        FilterKind::HessianOfGaussianEigenvalues
+    }
+    fn sigma(&self) -> f32{
+        self.sigma
+    }
+    /// The second derivative along x, the mixed derivative, then the second derivative along y
+    fn kernels(&self) -> Vec<SeparableKernel>{
+        let smoothing = gaussian_kernel_1d(self.sigma, 0, gaussian_radius(self.sigma, 0));
+        let first = gaussian_kernel_1d(self.sigma, 1, gaussian_radius(self.sigma, 1));
+        let second = gaussian_kernel_1d(self.sigma, 2, gaussian_radius(self.sigma, 2));
+        vec![
+            SeparableKernel{x: second.clone(), y: smoothing.clone()},
+            SeparableKernel{x: first.clone(), y: first},
+            SeparableKernel{x: smoothing, y: second},
+        ]
+    }
+    fn components_from_responses(&self, responses: &[f32]) -> Vec<f32>{
+        eigenvalues_2d(responses[0], responses[1], responses[2]).to_vec()
+    }
+    fn write_wgsl_components(
+        &self, out: &mut dyn std::fmt::Write, responses: &[String], components: &[String]
+    ) -> std::fmt::Result{
+        let (xx, xy, yy) = (&responses[0], &responses[1], &responses[2]);
+        let (largest, smallest) = (&components[0], &components[1]);
+        // same as `eigenvalues_2d`, for all channels at once
+        write!(out, "
+                let {largest}_mean = ({xx} + {yy}) * 0.5;
+                let {largest}_half_diff = ({xx} - {yy}) * 0.5;
+                let {largest}_radius = sqrt({largest}_half_diff * {largest}_half_diff + {xy} * {xy});
+                let {largest} = {largest}_mean + {largest}_radius;
+                let {smallest} = {largest}_mean - {largest}_radius;"
+        )
+    }
+    fn clone_box(&self) -> Box<dyn Filter>{
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_hessian_eigenvalues(){
+    use super::apply_filter_on_cpu;
+
+    assert_eq!(eigenvalues_2d(1.0, 0.0, 3.0), [3.0, 1.0]);
+    assert_eq!(eigenvalues_2d(2.0, 2.0, 2.0), [4.0, 0.0]);
+    assert_eq!(eigenvalues_3d(2.0, 0.0, 0.0, -1.0, 0.0, 5.0), [5.0, 2.0, -1.0]);
+    assert_eq!(eigenvalues_3d(1.5, 0.0, 0.0, 1.5, 0.0, 1.5), [1.5; 3]);
+    // [[2, 1, 0], [1, 2, 0], [0, 0, 7]] has eigenvalues 7, 3 and 1
+    let eigenvalues = eigenvalues_3d(2.0, 1.0, 0.0, 2.0, 0.0, 7.0);
+    for (computed, expected) in eigenvalues.iter().zip([7.0, 3.0, 1.0]){
+        assert!((computed - expected).abs() < 1e-5, "{eigenvalues:?}");
+    }
+
+    // away from the borders, x² + xy has the Hessian [[2, 1], [1, 0]] at every scale
+    let image = image::RgbaImage::from_fn(12, 12, |x, y| image::Rgba([(x * x + x * y) as u8, 0, 0, 255]));
+    let expected = [1.0 + 2f32.sqrt(), 1.0 - 2f32.sqrt()];
+    for sigma in [0.7, 1.0]{
+        let filter = HessianOfGaussianEigenvalues::new(sigma);
+        let eigenvalues = apply_filter_on_cpu(&filter, &image, 0);
+        assert_eq!(eigenvalues.len(), 2);
+        let margin = filter.radius() as u32;
+        for (y, x) in (margin..12 - margin).flat_map(|y| (margin..12 - margin).map(move |x| (y, x))){
+            for (component, expected) in eigenvalues.iter().zip(expected){
+                let value = component[(y * 12 + x) as usize];
+                assert!((value - expected).abs() < 1e-3, "sigma {sigma} at ({x}, {y}) gives {value}, expected {expected}");
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::feature_spec::FilterKind;
+
+use super::gaussian_blur::{gaussian_kernel_1d, gaussian_radius};
+use super::{Filter, SeparableKernel};
+
+/// The sum of the second derivatives along x and y of the image smoothed with a Gaussian, like
+/// vigra's `laplacianOfGaussian`: each second derivative of a Gaussian is smoothed with a Gaussian
+/// of the same sigma along the other axis
+#[derive(Clone)]
+pub struct LaplacianOfGaussian{
+    pub sigma: f32,
+}
+
+impl LaplacianOfGaussian{
+    pub fn new(sigma: f32) -> Self{
+        Self{sigma}
+    }
+}
+
+impl Filter for LaplacianOfGaussian{
+    fn kind(&self) -> FilterKind{
+        FilterKind::LaplacianOfGaussian
+    }
+    fn sigma(&self) -> f32{
+        self.sigma
+    }
+    /// The second derivative along x, then the one along y
+    fn kernels(&self) -> Vec<SeparableKernel>{
+        let smoothing = gaussian_kernel_1d(self.sigma, 0, gaussian_radius(self.sigma, 0));
+        let derivative = gaussian_kernel_1d(self.sigma, 2, gaussian_radius(self.sigma, 2));
+        vec![
+            SeparableKernel{x: derivative.clone(), y: smoothing.clone()},
+            SeparableKernel{x: smoothing, y: derivative},
+        ]
+    }
+    fn components_from_responses(&self, responses: &[f32]) -> Vec<f32>{
+        vec![responses[0] + responses[1]]
+    }
+    fn write_wgsl_components(
+        &self, out: &mut dyn std::fmt::Write, responses: &[String], components: &[String]
+    ) -> std::fmt::Result{
+        write!(out, "
+                let {} = {} + {};", components[0], responses[0], responses[1])
+    }
+    fn clone_box(&self) -> Box<dyn Filter>{
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_laplacian_of_parabolas(){
+    use super::apply_filter_on_cpu;
+
+    // away from the borders, x² and y² both have a Laplacian of 2 at every scale
+    let image = image::RgbaImage::from_fn(15, 15, |x, y| image::Rgba([(x * x) as u8, (y * y) as u8, 100, 255]));
+    for sigma in [0.7, 1.0]{
+        let filter = LaplacianOfGaussian::new(sigma);
+        let margin = filter.radius() as u32;
+        for channel in [0, 1]{
+            let laplacian = &apply_filter_on_cpu(&filter, &image, channel)[0];
+            for (y, x) in (margin..15 - margin).flat_map(|y| (margin..15 - margin).map(move |x| (y, x))){
+                let value = laplacian[(y * 15 + x) as usize];
+                assert!((value - 2.0).abs() < 1e-3, "sigma {sigma}, channel {channel} at ({x}, {y}) gives {value}");
+            }
+        }
+        let flat = &apply_filter_on_cpu(&filter, &image, 2)[0];
+        assert!(flat.iter().all(|value| value.abs() < 1e-4));
+    }
+}
@@ -1,14 +1,115 @@
 pub mod combined_filters;
 
+pub mod difference_of_gaussians;
+
 pub mod gaussian_blur;
 
+pub mod gaussian_gradient_magnitude;
+
+pub mod hessian_of_gaussian;
+
+pub mod laplacian_of_gaussian;
+
+use crate::feature_spec::FilterKind;
+
+use gaussian_blur::reflect_index;
+
 pub struct CenterOffset {
     pub x: i32,
     pub y: i32,
     pub z: i32,
 }
 
-// pub trait IKernelGenerator{
-//     fn kernel_at(&self, sample_var: &SampleVar) -> f32;
-//     fn make_accumulate_statement(&self, sample_var: &SampleVar) -> String;
-// }
+/// A 2D kernel that is the outer product of two 1D kernels, so it can be applied in two passes. The
+/// response at `(x, y)` is `Σ input[y + dy][x + dx] * self.y[dy + radius_y] * self.x[dx + radius_x]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeparableKernel{
+    /// Weights for the offsets `-radius_x..=radius_x` along x
+    pub x: Vec<f32>,
+    /// Weights for the offsets `-radius_y..=radius_y` along y
+    pub y: Vec<f32>,
+}
+
+impl SeparableKernel{
+    pub fn radius_x(&self) -> usize{
+        self.x.len() / 2
+    }
+    pub fn radius_y(&self) -> usize{
+        self.y.len() / 2
+    }
+}
+
+/// A feature that is computed per channel from the responses of one or more [`SeparableKernel`]s,
+/// e.g. the magnitude of the responses of an x and a y derivative kernel. The pipeline runs each
+/// distinct kernel of all of its filters once, and then lets every filter combine its responses
+pub trait Filter{
+    fn kind(&self) -> FilterKind;
+    /// The scale of the filter, which [`Feature::sigma`](crate::feature_spec::Feature::sigma) refers to
+    fn sigma(&self) -> f32;
+    /// The second scale of filters that have one, which [`Feature::inner_sigma`](crate::feature_spec::Feature::inner_sigma)
+    /// refers to
+    fn inner_sigma(&self) -> Option<f32>{
+        None
+    }
+    /// How many values the filter produces for each channel
+    fn num_components(&self) -> usize{
+        self.kind().num_components()
+    }
+    /// The kernels whose responses the filter is computed from
+    fn kernels(&self) -> Vec<SeparableKernel>;
+    /// How far from a pixel the filter looks
+    fn radius(&self) -> usize{
+        self.kernels().iter().map(|kernel| kernel.radius_x().max(kernel.radius_y())).max().unwrap_or(0)
+    }
+    /// The components for one channel, from the responses of [`Self::kernels`] in that channel
+    fn components_from_responses(&self, responses: &[f32]) -> Vec<f32>;
+    /// Writes WGSL that declares the `vec3<f32>` variables named in `components` from the `vec3<f32>`
+    /// expressions in `responses`, doing what [`Self::components_from_responses`] does for all channels at once
+    fn write_wgsl_components(
+        &self, out: &mut dyn std::fmt::Write, responses: &[String], components: &[String]
+    ) -> std::fmt::Result;
+    fn clone_box(&self) -> Box<dyn Filter>;
+}
+
+impl Clone for Box<dyn Filter>{
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Computes `filter` on channel `channel` of `image` on the CPU, reflecting at the borders like the
+/// shader does. Returns one row-major image per component
+pub fn apply_filter_on_cpu(filter: &dyn Filter, image: &image::RgbaImage, channel: usize) -> Vec<Vec<f32>>{
+    let (width, height) = (i64::from(image.width()), i64::from(image.height()));
+    let responses: Vec<Vec<f32>> = filter.kernels().iter()
+        .map(|kernel| {
+            let (radius_x, radius_y) = (kernel.radius_x() as i64, kernel.radius_y() as i64);
+            let rows: Vec<f32> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    (-radius_x..=radius_x).zip(&kernel.x)
+                        .map(|(dx, weight)| {
+                            let sample = image.get_pixel(reflect_index(x + dx, width) as u32, y as u32)[channel];
+                            f32::from(sample) * weight
+                        })
+                        .sum()
+                })
+                .collect();
+            (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    (-radius_y..=radius_y).zip(&kernel.y)
+                        .map(|(dy, weight)| rows[(reflect_index(y + dy, height) * width + x) as usize] * weight)
+                        .sum()
+                })
+                .collect()
+        })
+        .collect();
+    let num_pixels = (width * height) as usize;
+    let mut components = vec![Vec::with_capacity(num_pixels); filter.num_components()];
+    for pixel_idx in 0..num_pixels{
+        let pixel_responses: Vec<f32> = responses.iter().map(|response| response[pixel_idx]).collect();
+        for (component, value) in components.iter_mut().zip(filter.components_from_responses(&pixel_responses)){
+            component.push(value);
+        }
+    }
+    components
+}
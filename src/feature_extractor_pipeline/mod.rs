@@ -5,3 +5,5 @@ pub mod output_buffer;
 pub mod pipeline;
 pub mod reader_buffer;
 pub mod download_buffer;
+pub mod forest_buffer;
+pub mod response_texture;
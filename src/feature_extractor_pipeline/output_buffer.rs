@@ -1,26 +1,148 @@
-use std::{fmt::Display, marker::PhantomData, time::Instant};
+use std::{collections::{BTreeMap, BTreeSet}, fmt::Display, marker::PhantomData, time::Instant};
 
-use nalgebra::Vector2;
+use nalgebra::Vector4;
 
-use crate::{util::{Binding, Extent3dExt, Group, MegsPerMs}, wgsl::ShaderTypeExt};
+use crate::{feature_spec::Feature, util::{Binding, Group, MegsPerMs}, wgsl::ShaderTypeExt};
 
-use super::kernel::gaussian_blur::GaussianBlur;
+use super::kernel::{Filter, SeparableKernel};
+use super::pipeline::Convolution;
 
-pub struct OutputBufferSlot<T, const KSIDE: usize> {
+/// The order of the values of an [`OutputBufferSlot`] with more than one channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChannelLayout{
+    /// `[z][y][x][channel]`, the way numpy images are usually laid out
+    ChannelLast,
+    /// `[channel][z][y][x]`, i.e. one whole image after the other
+    ChannelFirst,
+}
+
+/// How the values of an [`OutputBufferSlot`] are stored in its WGSL array
+pub trait OutputValue{
+    /// Element type of the WGSL array
+    fn wgsl_element_type() -> String;
+    const ELEMENT_BYTES: usize;
+    /// How many values share a single array element
+    const VALUES_PER_ELEMENT: usize;
+    /// A WGSL statement that stores the `f32` in `value_expr` as value number `value_idx_expr` (a `u32`) of `buffer_name`
+    fn wgsl_store(buffer_name: &str, value_idx_expr: &str, value_expr: &str) -> String;
+}
+
+macro_rules! impl_OutputValue_for_shader_type {($type:ty) => {
+    impl OutputValue for $type {
+        fn wgsl_element_type() -> String {
+            <$type>::wgsl_type_name()
+        }
+        const ELEMENT_BYTES: usize = size_of::<$type>();
+        const VALUES_PER_ELEMENT: usize = 1;
+        fn wgsl_store(buffer_name: &str, value_idx_expr: &str, value_expr: &str) -> String {
+            format!("{buffer_name}[{value_idx_expr}] = {value_expr};")
+        }
+    }
+};}
+impl_OutputValue_for_shader_type!(f32);
+impl_OutputValue_for_shader_type!(Vector4<f32>);
+
+/// An `f32` in `0.0..=1.0`, stored as a `u8` scaled to `0..=255`
+pub struct Unorm8;
+/// An `f32` in `0.0..=1.0`, stored as a `u16` scaled to `0..=65535`
+pub struct Unorm16;
+/// The lowest 8 bits of a `u32`
+pub struct PackedU8;
+/// The lowest 16 bits of a `u32`
+pub struct PackedU16;
+
+/// Stores values that are smaller than a word by OR-ing them into their part of the word. The values of
+/// one word can come from different invocations, so the words are atomics, which start out as 0 in fresh buffers
+fn wgsl_store_packed(buffer_name: &str, value_idx_expr: &str, bits_expr: &str, bits_per_value: usize) -> String{
+    let values_per_word = 32 / bits_per_value;
+    format!(
+        "{{ let value_idx = {value_idx_expr}; \
+        atomicOr(&{buffer_name}[value_idx / {values_per_word}u], ({bits_expr}) << ({bits_per_value}u * (value_idx % {values_per_word}u))); }}"
+    )
+}
+
+impl OutputValue for Unorm8 {
+    fn wgsl_element_type() -> String {
+        "atomic<u32>".into()
+    }
+    const ELEMENT_BYTES: usize = size_of::<u32>();
+    const VALUES_PER_ELEMENT: usize = 4;
+    fn wgsl_store(buffer_name: &str, value_idx_expr: &str, value_expr: &str) -> String {
+        let bits_expr = format!("u32(round(clamp({value_expr}, 0.0, 1.0) * 255.0))");
+        wgsl_store_packed(buffer_name, value_idx_expr, &bits_expr, 8)
+    }
+}
+
+impl OutputValue for Unorm16 {
+    fn wgsl_element_type() -> String {
+        "atomic<u32>".into()
+    }
+    const ELEMENT_BYTES: usize = size_of::<u32>();
+    const VALUES_PER_ELEMENT: usize = 2;
+    fn wgsl_store(buffer_name: &str, value_idx_expr: &str, value_expr: &str) -> String {
+        let bits_expr = format!("u32(round(clamp({value_expr}, 0.0, 1.0) * 65535.0))");
+        wgsl_store_packed(buffer_name, value_idx_expr, &bits_expr, 16)
+    }
+}
+
+impl OutputValue for PackedU8 {
+    fn wgsl_element_type() -> String {
+        "atomic<u32>".into()
+    }
+    const ELEMENT_BYTES: usize = size_of::<u32>();
+    const VALUES_PER_ELEMENT: usize = 4;
+    fn wgsl_store(buffer_name: &str, value_idx_expr: &str, value_expr: &str) -> String {
+        wgsl_store_packed(buffer_name, value_idx_expr, &format!("{value_expr} & 0xffu"), 8)
+    }
+}
+
+impl OutputValue for PackedU16 {
+    fn wgsl_element_type() -> String {
+        "atomic<u32>".into()
+    }
+    const ELEMENT_BYTES: usize = size_of::<u32>();
+    const VALUES_PER_ELEMENT: usize = 2;
+    fn wgsl_store(buffer_name: &str, value_idx_expr: &str, value_expr: &str) -> String {
+        wgsl_store_packed(buffer_name, value_idx_expr, &format!("{value_expr} & 0xffffu"), 16)
+    }
+}
+
+/// A storage buffer with `num_channels` values for every pixel of an image
+pub struct OutputBufferSlot<T> {
     pub name: String,
     pub group: Group,
     pub binding: Binding,
     pub img_extent: wgpu::Extent3d,
+    pub num_channels: usize,
+    pub channel_layout: ChannelLayout,
     pub marker: PhantomData<T>,
 }
 
-impl<T: ShaderTypeExt, const KSIDE: usize> OutputBufferSlot<T, KSIDE> {
-    #[allow(non_snake_case)]
-    pub fn wgsl_indexing_from_kernIdx_xyzOffset(&self, xyz_offset_expr: &str) -> String{
-        format!("[{xyz_offset_expr}.z][{xyz_offset_expr}.y][{xyz_offset_expr}.x]")
+impl<T: OutputValue> OutputBufferSlot<T> {
+    pub fn num_values(&self) -> usize{
+        let wgpu::Extent3d{width, height, depth_or_array_layers: depth} = self.img_extent;
+        (width * height * depth) as usize * self.num_channels
+    }
+    fn num_elements(&self) -> usize{
+        self.num_values().div_ceil(T::VALUES_PER_ELEMENT)
+    }
+    /// WGSL expression of the position in the buffer of channel `channel_expr` of the pixel at `xyz_expr`
+    pub fn wgsl_value_idx(&self, xyz_expr: &str, channel_expr: &str) -> String{
+        let wgpu::Extent3d{width, height, depth_or_array_layers: depth} = self.img_extent;
+        let num_channels = self.num_channels;
+        let pixel_idx = format!("(({xyz_expr}.z * {height}u + {xyz_expr}.y) * {width}u + {xyz_expr}.x)");
+        match self.channel_layout {
+            ChannelLayout::ChannelLast => format!("({pixel_idx} * {num_channels}u + {channel_expr})"),
+            ChannelLayout::ChannelFirst => format!("(({channel_expr}) * {}u + {pixel_idx})", width * height * depth),
+        }
+    }
+    /// A WGSL statement that stores `value_expr` as channel `channel_expr` of the pixel at `xyz_expr`.
+    /// The value is a `T` for WGSL types, an `f32` for the normalized formats and a `u32` for the packed integers
+    pub fn wgsl_store(&self, xyz_expr: &str, channel_expr: &str, value_expr: &str) -> String{
+        T::wgsl_store(&self.name, &self.wgsl_value_idx(xyz_expr, channel_expr), value_expr)
     }
     pub fn output_buffer_size(&self) -> u64{
-        self.img_extent.to_buffer_size::<T>() //FIXME: assumes oiutput has same simensions as img? maybe this is ok
+        (self.num_elements() * T::ELEMENT_BYTES) as u64
     }
     pub fn create_output_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
         let size = self.output_buffer_size();
@@ -49,36 +171,118 @@ impl<T: ShaderTypeExt, const KSIDE: usize> OutputBufferSlot<T, KSIDE> {
     }
 }
 
-impl<T: ShaderTypeExt, const KSIDE: usize> Display for OutputBufferSlot<T, KSIDE>{
+impl<T: OutputValue> Display for OutputBufferSlot<T>{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let name = &self.name;
         let group = &self.group;
         let binding = &self.binding;
-        let img_array_type = self.img_extent.to_wgsl_array_type::<T>();
+        let element_type = T::wgsl_element_type();
+        let num_elements = self.num_elements();
         write!(
             f,
-            "@group({group}) @binding({binding}) var<storage, read_write> {name} : {img_array_type};",
-            // "@group({group}) @binding({binding}) var<storage, read_write> {name} : array<{img_array_type}, {num_kernels}>;",
+            "@group({group}) @binding({binding}) var<storage, read_write> {name} : array<{element_type}, {num_elements}>;",
         )
     }
 }
 
-pub struct KernelsInBuffSlot<const KSIDE: usize> {
+/// The type-independent parts of an [`OutputBufferSlot`], for outputs whose value type is picked at runtime
+pub trait AnyOutputBufferSlot: Display{
+    fn binding(&self) -> Binding;
+    fn wgsl_store(&self, xyz_expr: &str, channel_expr: &str, value_expr: &str) -> String;
+    fn output_buffer_size(&self) -> u64;
+    fn create_output_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer;
+    fn to_bind_group_layout_entry(&self) -> wgpu::BindGroupLayoutEntry;
+}
+
+impl<T: OutputValue> AnyOutputBufferSlot for OutputBufferSlot<T>{
+    fn binding(&self) -> Binding{
+        self.binding
+    }
+    fn wgsl_store(&self, xyz_expr: &str, channel_expr: &str, value_expr: &str) -> String{
+        OutputBufferSlot::wgsl_store(self, xyz_expr, channel_expr, value_expr)
+    }
+    fn output_buffer_size(&self) -> u64{
+        OutputBufferSlot::output_buffer_size(self)
+    }
+    fn create_output_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer{
+        OutputBufferSlot::create_output_buffer(self, device)
+    }
+    fn to_bind_group_layout_entry(&self) -> wgpu::BindGroupLayoutEntry{
+        OutputBufferSlot::to_bind_group_layout_entry(self)
+    }
+}
+
+/// Mirrors a coordinate into `0..=upper` without repeating the border pixel, like
+/// [`reflect_index`](super::kernel::gaussian_blur::reflect_index), so that borders are treated like vigra does
+const WGSL_REFLECT_COORD: &str = "
+            fn reflect_coord(coord: i32, upper: i32) -> i32 {
+                if (upper == 0) {
+                    return 0;
+                }
+                // mirroring is symmetric around 0, and `%` of negative numbers differs between backends
+                let period = 2 * upper;
+                let wrapped = abs(coord) % period;
+                return select(wrapped, period - wrapped, wrapped > upper);
+            }";
+
+pub struct KernelsInBuffSlot {
     name: String,
     group: Group,
     binding: Binding,
-    kernels: Vec<GaussianBlur<KSIDE>>,
+    filters: Vec<Box<dyn Filter>>,
+    convolution: Convolution,
+    /// Where each distinct 1D kernel of the filters starts in the buffer, since they have different sizes
+    offsets: Vec<usize>,
+    /// The radius of each distinct 1D kernel
+    radii: Vec<usize>,
+    /// Each distinct [`SeparableKernel`] of the filters, as the indices of its x and y 1D kernels
+    separable_kernels: Vec<(usize, usize)>,
+    /// For each filter, the indices into `separable_kernels` of its [`Filter::kernels`]
+    filter_kernels: Vec<Vec<usize>>,
     buffer: wgpu::Buffer,
 }
-impl<const KSIDE: usize> KernelsInBuffSlot<KSIDE> {
+impl KernelsInBuffSlot {
+    /// Packs every distinct 1D kernel of the filters into one buffer, one after the other, so that
+    /// filters sharing a kernel (e.g. the Gaussians of different filters at the same scale) share its response
     pub fn new(
         device: &wgpu::Device,
         name: String,
         group: Group,
         binding: Binding,
-        kernels: Vec<GaussianBlur<KSIDE>>,
+        filters: Vec<Box<dyn Filter>>,
+        convolution: Convolution,
     ) -> Self {
-        let buffer_byte_length = kernels[0].required_size_in_bytes() * kernels.len(); //FIXME: [0]
+        let start = Instant::now();
+        let mut kernels_1d: Vec<Vec<f32>> = vec![];
+        let mut kernel_1d_idx = |weights: Vec<f32>| match kernels_1d.iter().position(|kernel| *kernel == weights) {
+            Some(idx) => idx,
+            None => {
+                kernels_1d.push(weights);
+                kernels_1d.len() - 1
+            },
+        };
+        let mut separable_kernels: Vec<(usize, usize)> = vec![];
+        let filter_kernels: Vec<Vec<usize>> = filters.iter()
+            .map(|filter| filter.kernels().into_iter()
+                .map(|SeparableKernel{x, y}| {
+                    let kernel = (kernel_1d_idx(x), kernel_1d_idx(y));
+                    separable_kernels.iter().position(|known| *known == kernel).unwrap_or_else(|| {
+                        separable_kernels.push(kernel);
+                        separable_kernels.len() - 1
+                    })
+                })
+                .collect()
+            )
+            .collect();
+        let offsets: Vec<usize> = kernels_1d.iter()
+            .scan(0, |next_offset, weights| {
+                let offset = *next_offset;
+                *next_offset += weights.len();
+                Some(offset)
+            })
+            .collect();
+        let radii: Vec<usize> = kernels_1d.iter().map(|weights| weights.len() / 2).collect();
+        let buffer_byte_length = kernels_1d.iter().map(Vec::len).sum::<usize>() * std::mem::size_of::<f32>();
 
         let buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some(&format!("kernel_buffer__{name}")),
@@ -90,19 +294,8 @@ impl<const KSIDE: usize> KernelsInBuffSlot<KSIDE> {
         {
             let mut bytes_slice = buffer.slice(..).get_mapped_range_mut();
             let kernel_values: &mut [f32] = bytemuck::cast_slice_mut(&mut bytes_slice);
+            kernel_values.copy_from_slice(&kernels_1d.concat());
 
-            let iradius = i64::try_from((KSIDE - 1) / 2).unwrap();
-            let mut offset: usize = 0;
-
-            let start = Instant::now();
-            for y in -iradius..=iradius{
-                for x in -iradius..=iradius{
-                    for kern in &kernels{
-                        kernel_values[offset] = kern.kernel_at(Vector2::new(x, y));
-                        offset += 1;
-                    }
-                }
-            }
             let duration = Instant::now() - start;
             let megabytes_per_s = MegsPerMs::from_num_bytes_duration(&*bytes_slice, duration);
             eprintln!("Copied {buffer_byte_length} bytes form cpu to GPU in {duration:?} at {megabytes_per_s}");
@@ -110,48 +303,139 @@ impl<const KSIDE: usize> KernelsInBuffSlot<KSIDE> {
 
         buffer.unmap();
         Self{
-            name, group, binding, buffer, kernels
+            name, group, binding, buffer, filters, convolution, offsets, radii, separable_kernels, filter_kernels
         }
     }
-    pub fn kernels(&self) -> &[GaussianBlur<KSIDE>] {
-        &self.kernels
+    pub fn filters(&self) -> &[Box<dyn Filter>] {
+        &self.filters
+    }
+    pub fn convolution(&self) -> Convolution {
+        self.convolution
     }
-    pub fn radius(&self) -> usize{
-        (KSIDE - 1) / 2
+    /// Index of the filter that computes `feature`, if this slot has it
+    pub fn filter_idx_for(&self, feature: &Feature) -> Option<usize>{
+        //FIXME: assumes input image has 3 channels
+        if feature.channel >= 3{
+            return None
+        }
+        self.filters.iter().position(|filter| {
+            filter.kind() == feature.filter && filter.sigma() == feature.sigma && filter.inner_sigma() == feature.inner_sigma
+                && feature.component < filter.num_components()
+        })
     }
-    pub fn write_wgsl_feature_calcs(&self, mut out: &mut impl std::fmt::Write) -> Result<(), std::fmt::Error> {
-        let radius = self.radius();
+    /// The WGSL expression holding channel `channel` of component `component` of filter `f_idx`
+    pub fn wgsl_feature_expr(f_idx: usize, component: usize, channel: usize) -> String{
+        format!("feature_{f_idx}_{component}[{channel}]")
+    }
+    /// The separable kernels that the `(filter index, channel)` pairs in `used_responses` need, each
+    /// with the channels it is needed for
+    fn used_separable_kernels(&self, used_responses: &BTreeSet<(usize, usize)>) -> Vec<(usize, Vec<usize>)>{
+        let mut channels_by_kernel = BTreeMap::<usize, BTreeSet<usize>>::new();
+        for (f_idx, channel) in used_responses{
+            for kernel_idx in &self.filter_kernels[*f_idx]{
+                channels_by_kernel.entry(*kernel_idx).or_default().insert(*channel);
+            }
+        }
+        channels_by_kernel.into_iter().map(|(kernel_idx, channels)| (kernel_idx, channels.into_iter().collect())).collect()
+    }
+    /// The distinct x kernels of the separable kernels that `used_responses` need. With
+    /// [`Convolution::Separable`], the position in this list is the kernel's layer in the response texture
+    pub fn used_x_kernels(&self, used_responses: &BTreeSet<(usize, usize)>) -> Vec<usize>{
+        let x_kernels: BTreeSet<usize> = self.used_separable_kernels(used_responses).iter()
+            .map(|(kernel_idx, _)| self.separable_kernels[*kernel_idx].0)
+            .collect();
+        x_kernels.into_iter().collect()
+    }
+    /// WGSL expression of the weight of 1D kernel `kernel_1d_idx` at `tap_expr`, an `i32` counting from
+    /// 0 at the kernel's first weight
+    fn wgsl_weight(&self, kernel_1d_idx: usize, tap_expr: &str) -> String{
         let slot_name = &self.name;
-        let num_kernels = self.kernels().len();
-
-        for (k_idx, _kernel) in self.kernels.iter().enumerate(){
+        format!("{slot_name}[{slot_name}_offsets[{kernel_1d_idx}] + {tap_expr}]")
+    }
+    /// WGSL statements that add `value_var * weight_expr` to the `channels` of `response_<kernel_idx>`
+    fn wgsl_accumulate(kernel_idx: usize, channels: &[usize], value_var: &str, weight_expr: &str) -> String{
+        match channels.len() {
+            3 => format!("
+                    response_{kernel_idx} += {value_var} * {weight_expr};"),
+            _ => channels.iter()
+                .map(|channel| format!("
+                    response_{kernel_idx}[{channel}] += {value_var}[{channel}] * {weight_expr};"))
+                .collect(),
+        }
+    }
+    /// Writes the convolutions that fill the `response_<kernel>: vec3<f32>` variables, and then lets
+    /// each filter compute its `feature_<filter>_<component>: vec3<f32>` variables from them. Only what
+    /// the `(filter index, channel)` pairs in `used_responses` need is computed. With
+    /// [`Convolution::Separable`], the convolutions are the vertical pass over the texture filled by
+    /// [`Self::write_wgsl_horizontal_pass`]
+    pub fn write_wgsl_feature_calcs(
+        &self, mut out: &mut impl std::fmt::Write, used_responses: &BTreeSet<(usize, usize)>, response_texture: &str
+    ) -> Result<(), std::fmt::Error> {
+        let used_x_kernels = self.used_x_kernels(used_responses);
+        for (kernel_idx, channels) in self.used_separable_kernels(used_responses){
+            let (x_kernel, y_kernel) = self.separable_kernels[kernel_idx];
+            let (radius_x, radius_y) = (self.radii[x_kernel], self.radii[y_kernel]);
             //FIXME: assumes input image has 3 channels
             write!(&mut out, "
-                var feature_{k_idx}: vec3<f32> = vec3(0.0, 0.0, 0.0);"
+                var response_{kernel_idx}: vec3<f32> = vec3(0.0, 0.0, 0.0);"
             )?;
-        }
-        write!(&mut out, "
-                var in_buf_kernels_offset: i32 = 0;
-                for (var y=-{radius}; y<={radius}; y++){{
-                    for (var x=-{radius}; x<={radius}; x++){{
-                        let offset = vec2<i32>(x, y);
+            match self.convolution {
+                Convolution::Direct => write!(&mut out, "
+                for (var y=-{radius_y}; y<={radius_y}; y++){{
+                    for (var x=-{radius_x}; x<={radius_x}; x++){{
                         let sample_coords: vec2<i32> = vec2<i32>(
-                            clamp(current_coords.x + offset.x, 0, texture_upper_limit.x),
-                            clamp(current_coords.y + offset.y, 0, texture_upper_limit.y),
+                            reflect_coord(current_coords.x + x, texture_upper_limit.x),
+                            reflect_coord(current_coords.y + y, texture_upper_limit.y),
                         );
-                        let sample = textureLoad(input_image, sample_coords, 0).xyz * 255.0;
-                        {}
-                        in_buf_kernels_offset += {num_kernels};
+                        // ilastik computes features on the raw 0..=255 values, not on 0.0..=1.0
+                        let sample = textureLoad(input_image, sample_coords, 0).xyz * 255.0;{}
                     }}
+                }}",
+                    Self::wgsl_accumulate(kernel_idx, &channels, "sample", &format!(
+                        "{} * {}", self.wgsl_weight(y_kernel, &format!("y + {radius_y}")), self.wgsl_weight(x_kernel, &format!("x + {radius_x}")),
+                    )),
+                )?,
+                Convolution::Separable => {
+                    let layer = used_x_kernels.iter().position(|used| *used == x_kernel).unwrap();
+                    write!(&mut out, "
+                for (var y=-{radius_y}; y<={radius_y}; y++){{
+                    let sample_coords = vec2<i32>(current_coords.x, reflect_coord(current_coords.y + y, texture_upper_limit.y));
+                    let row_response = textureLoad({response_texture}, sample_coords, {layer}, 0).xyz;{}
+                }}",
+                        Self::wgsl_accumulate(kernel_idx, &channels, "row_response", &self.wgsl_weight(y_kernel, &format!("y + {radius_y}"))),
+                    )?
+                },
+            }
+        }
+        let used_filters: BTreeSet<usize> = used_responses.iter().map(|(f_idx, _)| *f_idx).collect();
+        for f_idx in used_filters{
+            let filter = &self.filters[f_idx];
+            let responses: Vec<String> = self.filter_kernels[f_idx].iter().map(|kernel_idx| format!("response_{kernel_idx}")).collect();
+            let components: Vec<String> = (0..filter.num_components()).map(|component| format!("feature_{f_idx}_{component}")).collect();
+            filter.write_wgsl_components(out, &responses, &components)?;
+        }
+        Ok(())
+    }
+    /// Writes the body of the first pass of a [`Convolution::Separable`] convolution, which convolves
+    /// each row of the input with the used x kernels and stores each one's response in its layer
+    /// of `response_texture`
+    pub fn write_wgsl_horizontal_pass(
+        &self, mut out: &mut impl std::fmt::Write, used_responses: &BTreeSet<(usize, usize)>, response_texture: &str
+    ) -> Result<(), std::fmt::Error> {
+        for (layer, x_kernel) in self.used_x_kernels(used_responses).into_iter().enumerate(){
+            let radius = self.radii[x_kernel];
+            write!(&mut out, "
+                var row_response_{layer}: vec3<f32> = vec3(0.0, 0.0, 0.0);
+                for (var x=-{radius}; x<={radius}; x++){{
+                    let sample_coords = vec2<i32>(reflect_coord(current_coords.x + x, texture_upper_limit.x), current_coords.y);
+                    let sample = textureLoad(input_image, sample_coords, 0).xyz * 255.0;
+                    row_response_{layer} += sample * {};
                 }}
-            ",
-            self.kernels.iter().enumerate()
-                .map(|(k_idx, _kern)| format!("
-                        //FIXME: ilastik features don't go from 0 to 1.0, but from 0.0 to 255.0, i think
-                        feature_{k_idx} += sample * {slot_name}[in_buf_kernels_offset + {k_idx}];"))
-                .collect::<Vec<_>>()
-                .join("")
-        )
+                textureStore({response_texture}, current_coords, {layer}, vec4(row_response_{layer}, 0.0));",
+                self.wgsl_weight(x_kernel, &format!("x + {radius}")),
+            )?;
+        }
+        Ok(())
     }
     pub fn to_binding_type(&self) -> wgpu::BindingType {
         wgpu::BindingType::Buffer {
@@ -175,14 +459,18 @@ impl<const KSIDE: usize> KernelsInBuffSlot<KSIDE> {
         }
     }
 }
-impl<const KSIDE: usize> Display for KernelsInBuffSlot< KSIDE> {
+impl Display for KernelsInBuffSlot {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let name = &self.name;
         let group = &self.group;
         let binding = &self.binding;
+        let offsets: Vec<String> = self.offsets.iter().map(|offset| offset.to_string()).collect();
         write!(
             f,
-            "@group({group}) @binding({binding}) var<storage, read> {name} : array<f32>;",
+            "@group({group}) @binding({binding}) var<storage, read> {name} : array<f32>;
+            const {name}_offsets = array<i32, {}>({});
+            {WGSL_REFLECT_COORD}",
+            offsets.len(), offsets.join(", "),
         )
     }
 }
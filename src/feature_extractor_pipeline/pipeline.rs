@@ -1,38 +1,332 @@
+use std::collections::BTreeSet;
 use std::fmt::Write;
 
+use anyhow as ah;
 use nalgebra::Vector4;
 use wgpu::{BindGroupLayoutDescriptor, ShaderModuleDescriptor};
 
-use crate::decision_tree::RandomForest;
-use crate::util::{timeit, Binding, Extent3dExt, Group, ImageBufferExt, WorkgroupSize};
+use crate::decision_tree::{write_wgsl_argmax, RandomForest, Uncertainty, VotingMode};
+use crate::feature_spec::FeatureSpec;
+use crate::npy::{NpyArray, NpyElement};
+#[cfg(test)]
+use crate::feature_spec::FilterKind;
+use crate::palette::Palette;
+use crate::util::{copy_bytes, timeit, Binding, Extent3dExt, Group, ImageBufferExt, WorkgroupSize};
+#[cfg(test)]
+use crate::util::test_device;
 
 use super::download_buffer::DownloadBuffer;
+use super::forest_buffer::ForestBufferSlot;
 use super::input_texture::InputTextureSlot;
-use super::output_buffer::{KernelsInBuffSlot, OutputBufferSlot};
+use super::response_texture::ResponseTextureSlot;
+use super::output_buffer::{
+    AnyOutputBufferSlot, ChannelLayout, KernelsInBuffSlot, OutputBufferSlot, OutputValue, PackedU16, PackedU8, Unorm16, Unorm8,
+};
+use super::kernel::Filter;
+#[cfg(test)]
 use super::kernel::gaussian_blur::GaussianBlur;
 
-pub struct FeatureExtractorPipeline<const KSIDE: usize> {
+/// How the forest gets evaluated in the compute shader
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ForestEvaluation{
+    /// Every tree is compiled into nested `if/else` statements via `RandomForest::write_wgsl`
+    Inlined,
+    /// Trees are uploaded as a flattened node buffer and walked by a fixed loop, so a retrained
+    /// forest can be swapped in with [`FeatureExtractorPipeline::upload_forest`] without
+    /// recompiling the shader
+    NodeBuffer,
+}
+
+/// How the Gaussian kernels are applied in the compute shader
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Convolution{
+    /// Every pixel reads the whole `k × k` neighborhood of each kernel, costing `O(k²)` per kernel
+    Direct,
+    /// A first pass convolves the rows with the 1D factors of the kernels into a texture, and the
+    /// main shader convolves that texture's columns, costing `O(2k)` per kernel
+    Separable,
+}
+
+/// Knobs for the shader generated by [`FeatureExtractorPipeline::new`]
+#[derive(Debug, Clone)]
+pub struct PipelineOptions{
+    pub voting: VotingMode,
+    pub forest_evaluation: ForestEvaluation,
+    pub convolution: Convolution,
+    /// Also write out every feature of every pixel, in [`PipelineOutput::features`]
+    pub features: bool,
+    /// Also compute how sure the forest is about each pixel, in [`PipelineOutput::uncertainty`]
+    pub uncertainty: Option<Uncertainty>,
+    /// Also write out the class scores of each pixel, in [`PipelineOutput::probabilities`]
+    pub probabilities: Option<ProbabilityOutput>,
+    /// Also write out the predicted class index of each pixel, in [`PipelineOutput::labels`]
+    pub labels: Option<LabelFormat>,
+    /// Write the color of each pixel's class to [`PipelineOutput::predictions`]. At 16 bytes per pixel,
+    /// reading these back is what takes longest, so turn this off if the labels are enough
+    pub colors: bool,
+    /// The colors to draw classes in. Defaults to [`Palette::for_classes`] of the forest's class table
+    pub palette: Option<Palette>,
+    /// Blend the colors over the input image instead of writing them as they are, with each class
+    /// covering the input by its color's alpha times this opacity
+    pub overlay_opacity: Option<f32>,
+}
+
+/// How the per-class probabilities are stored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProbabilityFormat{
+    F32,
+    /// `0.0..=1.0` scaled to `0..=255`
+    Unorm8,
+    /// `0.0..=1.0` scaled to `0..=65535`
+    Unorm16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LabelFormat{
+    /// Up to 256 classes, packed four pixels to a word on the GPU
+    U8,
+    /// Up to 65536 classes, packed two pixels to a word on the GPU
+    U16,
+}
+
+impl LabelFormat{
+    pub fn max_num_classes(&self) -> usize{
+        match self{
+            Self::U8 => usize::from(u8::MAX) + 1,
+            Self::U16 => usize::from(u16::MAX) + 1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProbabilityOutput{
+    pub format: ProbabilityFormat,
+    /// Whether the classes of a pixel are next to each other, or each class is a whole image
+    pub layout: ChannelLayout,
+}
+
+impl Default for PipelineOptions{
+    fn default() -> Self {
+        Self{
+            voting: VotingMode::Soft,
+            forest_evaluation: ForestEvaluation::Inlined,
+            convolution: Convolution::Separable,
+            features: false,
+            uncertainty: None,
+            probabilities: None,
+            labels: None,
+            colors: true,
+            palette: None,
+            overlay_opacity: None,
+        }
+    }
+}
+
+/// What [`FeatureExtractorPipeline::process`] reads back for an image, one entry per pixel in row-major order
+pub struct PipelineOutput{
+    /// The color of the class predicted for each pixel, unless [`PipelineOptions::colors`] was turned off
+    pub predictions: Option<Vec<[f32; 4]>>,
+    /// Present if the pipeline was created with [`PipelineOptions::uncertainty`]
+    pub uncertainty: Option<Vec<f32>>,
+    /// Present if the pipeline was created with [`PipelineOptions::probabilities`]
+    pub probabilities: Option<ProbabilityMap>,
+    /// Present if the pipeline was created with [`PipelineOptions::labels`]
+    pub labels: Option<LabelImage>,
+    /// Present if the pipeline was created with [`PipelineOptions::features`], as `[pixel][feature]`
+    /// in the order of the forest's feature spec
+    pub features: Option<Vec<f32>>,
+}
+
+impl PipelineOutput{
+    /// [`Self::features`] as an array of shape `(height, width, num_features)`, like ilastik's feature maps
+    pub fn features_to_npy(&self, width: u32, height: u32) -> ah::Result<NpyArray<f32>>{
+        let Some(features) = &self.features else {
+            ah::bail!("Features were not read back; create the pipeline with `PipelineOptions::features`");
+        };
+        let num_pixels = (width * height) as usize;
+        if num_pixels == 0 || features.len() % num_pixels != 0{
+            ah::bail!("Can't split {} feature values between {width}x{height} pixels", features.len());
+        }
+        NpyArray::new(vec![height as usize, width as usize, features.len() / num_pixels], features.clone())
+    }
+}
+
+/// The index of the class predicted for each pixel
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelImage{
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+}
+
+impl LabelImage{
+    /// Takes apart the words that the shader packed the first `num_pixels` labels into, lowest bits first
+    pub fn unpack(words: &[u32], format: LabelFormat, num_pixels: usize) -> Self{
+        match format {
+            LabelFormat::U8 => Self::U8(
+                words.iter().flat_map(|word| [0, 8, 16, 24].map(|shift| (word >> shift) as u8)).take(num_pixels).collect()
+            ),
+            LabelFormat::U16 => Self::U16(
+                words.iter().flat_map(|word| [0, 16].map(|shift| (word >> shift) as u16)).take(num_pixels).collect()
+            ),
+        }
+    }
+    pub fn len(&self) -> usize{
+        match self{
+            Self::U8(labels) => labels.len(),
+            Self::U16(labels) => labels.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    /// The class index of the pixel with row-major index `pixel_idx`
+    pub fn get(&self, pixel_idx: usize) -> usize{
+        match self{
+            Self::U8(labels) => usize::from(labels[pixel_idx]),
+            Self::U16(labels) => usize::from(labels[pixel_idx]),
+        }
+    }
+    /// The labels as an array of shape `(height, width)` with elements of type `T`, failing if a
+    /// class index doesn't fit into `T`
+    pub fn to_npy<T: NpyElement + TryFrom<usize>>(&self, width: u32, height: u32) -> ah::Result<NpyArray<T>>{
+        let labels = (0..self.len())
+            .map(|pixel_idx| {
+                let label = self.get(pixel_idx);
+                T::try_from(label).map_err(|_| ah::anyhow!("Label {label} does not fit into '{}'", T::DESCR))
+            })
+            .collect::<ah::Result<Vec<T>>>()?;
+        NpyArray::new(vec![height as usize, width as usize], labels)
+    }
+}
+
+/// Which part of [`PipelineOutput`] an output buffer of the shader fills
+#[derive(Debug, Copy, Clone)]
+enum OutputKind{
+    Colors,
+    Features,
+    Uncertainty(Uncertainty),
+    Probabilities(ProbabilityOutput),
+    Labels(LabelFormat),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbabilityValues{
+    F32(Vec<f32>),
+    Unorm8(Vec<u8>),
+    Unorm16(Vec<u16>),
+}
+
+/// The probability of every class at every pixel, i.e. the `class_<k>_score` variables of the shader
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbabilityMap{
+    pub values: ProbabilityValues,
+    pub layout: ChannelLayout,
+    pub num_pixels: usize,
+    pub num_classes: usize,
+}
+
+impl ProbabilityMap{
+    /// Decodes the values that the shader wrote to the buffer in `format`
+    fn from_bytes(bytes: &[u8], format: ProbabilityFormat, layout: ChannelLayout, num_pixels: usize, num_classes: usize) -> Self{
+        let num_values = num_pixels * num_classes;
+        let values = match format {
+            ProbabilityFormat::F32 => ProbabilityValues::F32(
+                bytes.chunks_exact(4).take(num_values).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect()
+            ),
+            ProbabilityFormat::Unorm8 => ProbabilityValues::Unorm8(bytes[..num_values].to_vec()),
+            ProbabilityFormat::Unorm16 => ProbabilityValues::Unorm16(
+                bytes.chunks_exact(2).take(num_values).map(|b| u16::from_le_bytes(b.try_into().unwrap())).collect()
+            ),
+        };
+        Self{values, layout, num_pixels, num_classes}
+    }
+    /// The probability of class `class_idx` at the pixel with row-major index `pixel_idx`, as an `f32` in `0.0..=1.0`
+    pub fn get(&self, pixel_idx: usize, class_idx: usize) -> f32{
+        let value_idx = match self.layout {
+            ChannelLayout::ChannelLast => pixel_idx * self.num_classes + class_idx,
+            ChannelLayout::ChannelFirst => class_idx * self.num_pixels + pixel_idx,
+        };
+        match &self.values {
+            ProbabilityValues::F32(values) => values[value_idx],
+            ProbabilityValues::Unorm8(values) => f32::from(values[value_idx]) / f32::from(u8::MAX),
+            ProbabilityValues::Unorm16(values) => f32::from(values[value_idx]) / f32::from(u16::MAX),
+        }
+    }
+    /// The probabilities as `f32`s in `0.0..=1.0`, in an array of shape `(height, width, num_classes)`
+    /// if the map is [`ChannelLayout::ChannelLast`] or `(num_classes, height, width)` if it is
+    /// [`ChannelLayout::ChannelFirst`]
+    pub fn to_npy(&self, width: u32, height: u32) -> ah::Result<NpyArray<f32>>{
+        let (width, height) = (width as usize, height as usize);
+        if width * height != self.num_pixels{
+            ah::bail!("A {width}x{height} image doesn't have {} pixels", self.num_pixels);
+        }
+        let values: Vec<f32> = match &self.values {
+            ProbabilityValues::F32(values) => values.clone(),
+            ProbabilityValues::Unorm8(values) => values.iter().map(|value| f32::from(*value) / f32::from(u8::MAX)).collect(),
+            ProbabilityValues::Unorm16(values) => values.iter().map(|value| f32::from(*value) / f32::from(u16::MAX)).collect(),
+        };
+        let shape = match self.layout {
+            ChannelLayout::ChannelLast => vec![height, width, self.num_classes],
+            ChannelLayout::ChannelFirst => vec![self.num_classes, height, width],
+        };
+        NpyArray::new(shape, values)
+    }
+}
+
+pub struct FeatureExtractorPipeline {
     device: wgpu::Device,
     queue: wgpu::Queue,
     input_texture_slot: InputTextureSlot,
     kernels_bind_group: wgpu::BindGroup,
-    output_buffer_slot: OutputBufferSlot<Vector4<f32>, KSIDE>,
+    output_slots: Vec<(OutputKind, Box<dyn AnyOutputBufferSlot>)>,
+    img_extent: wgpu::Extent3d,
+    num_classes: usize,
+    forest_buffer_slot: Option<ForestBufferSlot>,
+    forest_bind_group: Option<wgpu::BindGroup>,
+    feature_spec: FeatureSpec,
     workgroup_size: WorkgroupSize,
     pipeline: wgpu::ComputePipeline,
+    /// The first pass of a [`Convolution::Separable`] pipeline
+    horizontal_pass: Option<HorizontalPass>,
+    /// The `(filter index, channel)` pairs that the shader computes
+    used_responses: BTreeSet<(usize, usize)>,
 }
-impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
+
+/// The pipeline that fills the response texture of a [`Convolution::Separable`] convolution, and its
+/// kernels and response texture bindings, which don't change between images
+struct HorizontalPass{
+    pipeline: wgpu::ComputePipeline,
+    kernels_bind_group: wgpu::BindGroup,
+}
+impl FeatureExtractorPipeline {
     pub const INOUT_GROUP: Group = Group(0);
     pub const KERNELS_GROUP: Group = Group(1);
+    pub const FOREST_GROUP: Group = Group(2);
 
     pub fn new(
         device: wgpu::Device,
         queue: wgpu::Queue,
         workgroup_size: WorkgroupSize,
-        kernels: Vec<GaussianBlur<KSIDE>>,
+        kernels: Vec<Box<dyn Filter>>,
         forest: &RandomForest,
+        options: PipelineOptions,
         img_extent: wgpu::Extent3d,
-    ) -> Self {
-        assert!(forest.highest_feature_idx() + 1 <= kernels.len() * 3);
+    ) -> Result<Self, String> {
+        // forests from scikit don't say what their features are, so assume they match the kernels
+        let feature_spec = match forest.feature_spec() {
+            Some(spec) => spec.clone(),
+            None => FeatureSpec::from_filters_with_inner_sigmas(
+                &kernels.iter().map(|kernel| (kernel.kind(), kernel.sigma(), kernel.inner_sigma())).collect::<Vec<_>>(),
+                3, //FIXME: assuming image is RGB
+            ),
+        };
+        let num_features = feature_spec.len();
+        if forest.highest_feature_idx() >= num_features{
+            return Err(format!(
+                "Forest splits on feature {}, but the feature spec only describes {num_features} features",
+                forest.highest_feature_idx(),
+            ))
+        }
         let input_texture_view_dimension = match img_extent.depth_or_array_layers {
             1 => wgpu::TextureViewDimension::D2,
             _ => wgpu::TextureViewDimension::D3,
@@ -45,26 +339,121 @@ impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
             wgpu::TextureSampleType::Float { filterable: false },
             input_texture_view_dimension,
         );
-        let output_buffer_slot = OutputBufferSlot::<Vector4<f32>, KSIDE>{
-            name: "output_features_buf".into(),
-            group: Self::INOUT_GROUP,
-            binding: Binding(1),
-            img_extent,
-            marker: std::marker::PhantomData,
-        };
-        let kernel_buffer_slot: KernelsInBuffSlot<KSIDE> = KernelsInBuffSlot::new(
+        let num_classes = forest.num_classes();
+        let mut output_kinds = vec![];
+        if options.colors{
+            output_kinds.push(OutputKind::Colors);
+        }
+        if options.features{
+            output_kinds.push(OutputKind::Features);
+        }
+        output_kinds.extend(options.uncertainty.map(OutputKind::Uncertainty));
+        output_kinds.extend(options.probabilities.map(OutputKind::Probabilities));
+        output_kinds.extend(options.labels.map(OutputKind::Labels));
+        if output_kinds.is_empty(){
+            return Err("Pipeline has no outputs".into())
+        }
+        if let Some(format) = options.labels.filter(|format| num_classes > format.max_num_classes()){
+            return Err(format!("{format:?} labels can't hold {num_classes} classes"))
+        }
+        let palette = options.palette.clone().unwrap_or_else(|| Palette::for_classes(forest.classes()));
+        if palette.len() < num_classes{
+            return Err(format!("Palette has {} colors, but forest has {num_classes} classes", palette.len()))
+        }
+        let output_slots: Vec<(OutputKind, Box<dyn AnyOutputBufferSlot>)> = (1..).zip(output_kinds)
+            .map(|(binding, kind)| {
+                let slot = match kind {
+                    OutputKind::Colors => Self::output_slot::<Vector4<f32>>(
+                        "output_colors_buf", binding, img_extent, 1, ChannelLayout::ChannelLast
+                    ),
+                    OutputKind::Features => Self::output_slot::<f32>(
+                        "output_features_buf", binding, img_extent, num_features, ChannelLayout::ChannelLast
+                    ),
+                    OutputKind::Uncertainty(_) => Self::output_slot::<f32>(
+                        "output_uncertainty_buf", binding, img_extent, 1, ChannelLayout::ChannelLast
+                    ),
+                    OutputKind::Probabilities(ProbabilityOutput{format, layout}) => {
+                        let name = "output_probabilities_buf";
+                        match format {
+                            ProbabilityFormat::F32 => Self::output_slot::<f32>(name, binding, img_extent, num_classes, layout),
+                            ProbabilityFormat::Unorm8 => Self::output_slot::<Unorm8>(name, binding, img_extent, num_classes, layout),
+                            ProbabilityFormat::Unorm16 => Self::output_slot::<Unorm16>(name, binding, img_extent, num_classes, layout),
+                        }
+                    },
+                    OutputKind::Labels(LabelFormat::U8) => Self::output_slot::<PackedU8>(
+                        "output_labels_buf", binding, img_extent, 1, ChannelLayout::ChannelLast
+                    ),
+                    OutputKind::Labels(LabelFormat::U16) => Self::output_slot::<PackedU16>(
+                        "output_labels_buf", binding, img_extent, 1, ChannelLayout::ChannelLast
+                    ),
+                };
+                (kind, slot)
+            })
+            .collect();
+        let kernel_buffer_slot = KernelsInBuffSlot::new(
             &device,
             "in_buf_kernels".to_owned(),
             Self::KERNELS_GROUP,
             Binding(0),
             kernels,
+            options.convolution,
+        );
+        let forest_buffer_slot = match options.forest_evaluation {
+            ForestEvaluation::Inlined => None,
+            ForestEvaluation::NodeBuffer => Some(ForestBufferSlot::new(
+                "forest_buf".to_owned(),
+                Self::FOREST_GROUP,
+                Binding(0),
+                forest.num_classes(),
+            )),
+        };
+        let forest_buffer_decl = forest_buffer_slot.as_ref().map(|slot| slot.to_string()).unwrap_or_default();
+        // a node buffer can be swapped for a forest that splits on anything, so it needs every feature
+        let used_features: BTreeSet<usize> = match options.forest_evaluation {
+            ForestEvaluation::Inlined if !options.features => forest.used_features(),
+            _ => (0..num_features).collect(),
+        };
+        let mut used_responses = BTreeSet::<(usize, usize)>::new();
+        let mut feature_exprs = vec![String::from("0.0"); num_features];
+        for feature_idx in &used_features{
+            let feature = &feature_spec.features()[*feature_idx];
+            let Some(f_idx) = kernel_buffer_slot.filter_idx_for(feature) else {
+                return Err(format!("Forest needs feature {feature_idx}, {feature}, but no filter computes it"))
+            };
+            used_responses.insert((f_idx, feature.channel));
+            feature_exprs[*feature_idx] = KernelsInBuffSlot::wgsl_feature_expr(f_idx, feature.component, feature.channel);
+        }
+        let num_filters = kernel_buffer_slot.filters().len();
+        let num_used_filters = used_responses.iter().map(|(f_idx, _)| f_idx).collect::<BTreeSet<_>>().len();
+        log::info!(
+            "Forest uses {} of {num_features} features; skipping {} of {num_filters} filters and {} filter channels",
+            used_features.len(),
+            num_filters - num_used_filters,
+            num_filters * 3 - used_responses.len(), //FIXME: assuming image is RGB
         );
-        let output_name = &output_buffer_slot.name;
+
+        // the separable convolution's first pass gets its own shader, writing to a texture the main shader reads
+        let response_texture_slot = (options.convolution == Convolution::Separable).then(|| ResponseTextureSlot::new(
+            &device,
+            "horizontal_responses".to_owned(),
+            Self::KERNELS_GROUP,
+            Binding(1),
+            img_extent,
+            kernel_buffer_slot.used_x_kernels(&used_responses).len() as u32,
+        ));
+        let horizontal_pass = response_texture_slot.as_ref().map(|slot| {
+            Self::create_horizontal_pass(&device, &workgroup_size, &input_texture_slot, &kernel_buffer_slot, slot, &used_responses)
+        });
+
+        let output_buffer_decls: Vec<String> = output_slots.iter().map(|(_, slot)| slot.to_string()).collect();
+        let response_texture_decl = response_texture_slot.as_ref().map(|slot| slot.to_wgsl_read_declaration()).unwrap_or_default();
         let mut code = String::with_capacity(1024 * 1024);
         write!(&mut code, "
             {input_texture_slot}
-            {output_buffer_slot}
+            {}
             {kernel_buffer_slot}
+            {response_texture_decl}
+            {forest_buffer_decl}
 
             @compute {workgroup_size}
             fn extract_features(
@@ -77,22 +466,69 @@ impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
                 if(global_id.x >= dimensions.x || global_id.y >= dimensions.y) {{
                     return;
                 }}
-        ").unwrap();
+        ",
+            output_buffer_decls.join("\n            "),
+        ).unwrap();
 
-        kernel_buffer_slot.write_wgsl_feature_calcs(&mut code).unwrap();
+        let response_texture_name = response_texture_slot.as_ref().map(|slot| slot.name()).unwrap_or_default();
+        kernel_buffer_slot.write_wgsl_feature_calcs(&mut code, &used_responses, response_texture_name).unwrap();
 
-        forest.write_wgsl(&mut code).unwrap();
+        match &forest_buffer_slot {
+            None => forest.write_wgsl(&mut code, options.voting, &feature_exprs).unwrap(),
+            Some(slot) => {
+                write!(&mut code, "
+                var features = array<f32, {num_features}>({});",
+                    feature_exprs.join(", ")
+                ).unwrap();
+                slot.write_wgsl_traversal(&mut code, options.voting).unwrap();
+            }
+        }
 
-        let output_indexing = output_buffer_slot.wgsl_indexing_from_kernIdx_xyzOffset("global_id");
-        write!(&mut code, "
-            if class_0_score > class_1_score && class_0_score > class_2_score {{
-                {output_name}{output_indexing} = vec4(255.0, 0.0, 0.0, 1.0); //FIXME! hardcoded alpha channel!!
-            }} else if class_1_score > class_0_score && class_1_score > class_2_score {{
-                {output_name}{output_indexing} = vec4(0, 255.0, 0.0, 1.0); //FIXME! hardcoded alpha channel!!
-            }} else {{
-                {output_name}{output_indexing} = vec4(0.0, 0.0, 255.0, 1.0); //FIXME! hardcoded alpha channel!!
-            }}"
-        ).unwrap();
+        write_wgsl_argmax(&mut code, num_classes).unwrap();
+        let class_names: Vec<String> = forest.classes().iter().map(|class| format!("{} = '{}'", class.index, class.name)).collect();
+        log::info!("Classifying into {}", class_names.join(", "));
+        for (kind, slot) in &output_slots{
+            match kind {
+                OutputKind::Colors => {
+                    palette.write_wgsl(&mut code).unwrap();
+                    let color = match options.overlay_opacity {
+                        Some(opacity) => {
+                            write!(&mut code, "
+                let class_color = class_colors[predicted_class];
+                let blend = class_color.a * {opacity:?};
+                let input_color = textureLoad(input_image, current_coords, 0).rgb;").unwrap();
+                            "vec4(mix(input_color, class_color.rgb, blend), 1.0)"
+                        },
+                        None => "class_colors[predicted_class]",
+                    };
+                    write!(&mut code, "
+                {}", slot.wgsl_store("global_id", "0u", color)).unwrap();
+                },
+                OutputKind::Features => for (feature_idx, feature_expr) in feature_exprs.iter().enumerate(){
+                    write!(&mut code, "
+                {}",
+                        slot.wgsl_store("global_id", &format!("{feature_idx}u"), feature_expr),
+                    ).unwrap();
+                },
+                OutputKind::Uncertainty(uncertainty) => {
+                    uncertainty.write_wgsl(&mut code, num_classes).unwrap();
+                    write!(&mut code, "
+                {}",
+                        slot.wgsl_store("global_id", "0u", "uncertainty"),
+                    ).unwrap();
+                },
+                OutputKind::Probabilities(_) => for class_idx in 0..num_classes{
+                    write!(&mut code, "
+                {}",
+                        slot.wgsl_store("global_id", &format!("{class_idx}u"), &format!("class_{class_idx}_score")),
+                    ).unwrap();
+                },
+                OutputKind::Labels(_) => write!(&mut code, "
+                {}",
+                    slot.wgsl_store("global_id", "0u", "predicted_class"),
+                ).unwrap(),
+            }
+        }
 
         write!(&mut code, "
             }} //closes extract_features fn
@@ -111,38 +547,58 @@ impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
         });
 
         // ------------------ Layout --------------------
+        let mut inout_layout_entries = vec![input_texture_slot.to_bind_group_layout_entry()];
+        inout_layout_entries.extend(output_slots.iter().map(|(_, slot)| slot.to_bind_group_layout_entry()));
         let inout_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("inout_group_layout"),
-            entries: &[
-                input_texture_slot.to_bind_group_layout_entry(),
-                output_buffer_slot.to_bind_group_layout_entry(),
-            ],
+            entries: &inout_layout_entries,
         });
 
+        let mut kernels_layout_entries = vec![kernel_buffer_slot.to_bind_group_layout_entry()];
+        kernels_layout_entries.extend(response_texture_slot.as_ref().map(|slot| slot.to_read_bind_group_layout_entry()));
         let kernels_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor{
             label: Some("kernels_group_layout"),
-            entries: &[kernel_buffer_slot.to_bind_group_layout_entry()],
+            entries: &kernels_layout_entries,
         });
+        let mut kernels_entries = vec![kernel_buffer_slot.to_bind_group_entry()];
+        kernels_entries.extend(response_texture_slot.as_ref().map(|slot| slot.to_bind_group_entry()));
 
+        let forest_bind_group_layout = forest_buffer_slot.as_ref().map(|slot| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor{
+                label: Some("forest_group_layout"),
+                entries: &[slot.to_bind_group_layout_entry()],
+            })
+        });
+
+        let mut bind_group_layouts = vec![&inout_bind_group_layout, &kernels_bind_group_layout];
+        bind_group_layouts.extend(&forest_bind_group_layout);
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("feature_extractor_pipeline_layout"),
             push_constant_ranges: &[],
-            bind_group_layouts: &[
-                &inout_bind_group_layout,
-                &kernels_bind_group_layout,
-            ],
+            bind_group_layouts: &bind_group_layouts,
         });
         // ------------------ END Layout --------------------
 
-        Self {
+        let forest_bind_group = forest_buffer_slot.as_ref()
+            .zip(forest_bind_group_layout.as_ref())
+            .map(|(slot, layout)| Self::create_forest_bind_group(&device, slot, layout, forest));
+
+        Ok(Self {
             input_texture_slot,
-            output_buffer_slot,
+            output_slots,
+            img_extent,
+            num_classes,
+            forest_buffer_slot,
+            forest_bind_group,
+            feature_spec,
             workgroup_size,
             kernels_bind_group: device.create_bind_group(&wgpu::BindGroupDescriptor{
                 label: Some("kernels_unifors_group"),
                 layout: &kernels_bind_group_layout,
-                entries: &[kernel_buffer_slot.to_bind_group_entry()],
+                entries: &kernels_entries,
             }),
+            horizontal_pass,
+            used_responses,
             pipeline: device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
                 label: Some("my_pipeline"),
                 entry_point: Some("extract_features"),
@@ -153,14 +609,136 @@ impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
             }),
             device,
             queue,
+        })
+    }
+    fn output_slot<T: OutputValue + 'static>(
+        name: &str, binding: u32, img_extent: wgpu::Extent3d, num_channels: usize, channel_layout: ChannelLayout
+    ) -> Box<dyn AnyOutputBufferSlot> {
+        Box::new(OutputBufferSlot::<T>{
+            name: name.to_owned(),
+            group: Self::INOUT_GROUP,
+            binding: Binding(binding),
+            img_extent,
+            num_channels,
+            channel_layout,
+            marker: std::marker::PhantomData,
+        })
+    }
+    fn create_horizontal_pass(
+        device: &wgpu::Device,
+        workgroup_size: &WorkgroupSize,
+        input_texture_slot: &InputTextureSlot,
+        kernel_buffer_slot: &KernelsInBuffSlot,
+        response_texture_slot: &ResponseTextureSlot,
+        used_responses: &BTreeSet<(usize, usize)>,
+    ) -> HorizontalPass {
+        let mut code = String::new();
+        write!(&mut code, "
+            {input_texture_slot}
+            {kernel_buffer_slot}
+            {}
+
+            @compute {workgroup_size}
+            fn horizontal_pass(
+                @builtin(global_invocation_id) global_id : vec3<u32>,
+            ) {{
+                let dimensions = textureDimensions(input_image);
+                let texture_upper_limit = vec2<i32>(dimensions.xy) - vec2<i32>(1, 1);
+                let current_coords = vec2<i32>(global_id.xy);
+
+                if(global_id.x >= dimensions.x || global_id.y >= dimensions.y) {{
+                    return;
+                }}",
+            response_texture_slot.to_wgsl_write_declaration(),
+        ).unwrap();
+        kernel_buffer_slot.write_wgsl_horizontal_pass(&mut code, used_responses, response_texture_slot.name()).unwrap();
+        write!(&mut code, "
+            }} //closes horizontal_pass fn
+        ").unwrap();
+
+        let shader_module = timeit("compiling horizontal pass shader", ||{
+            device.create_shader_module(ShaderModuleDescriptor {
+                label: Some("horizontal_pass_comp_shader"),
+                source: wgpu::ShaderSource::Wgsl(code.into()),
+            })
+        });
+        let inout_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("horizontal_pass_inout_group_layout"),
+            entries: &[input_texture_slot.to_bind_group_layout_entry()],
+        });
+        let kernels_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor{
+            label: Some("horizontal_pass_kernels_group_layout"),
+            entries: &[kernel_buffer_slot.to_bind_group_layout_entry(), response_texture_slot.to_write_bind_group_layout_entry()],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("horizontal_pass_pipeline_layout"),
+            push_constant_ranges: &[],
+            bind_group_layouts: &[&inout_bind_group_layout, &kernels_bind_group_layout],
+        });
+        HorizontalPass{
+            kernels_bind_group: device.create_bind_group(&wgpu::BindGroupDescriptor{
+                label: Some("horizontal_pass_kernels_group"),
+                layout: &kernels_bind_group_layout,
+                entries: &[kernel_buffer_slot.to_bind_group_entry(), response_texture_slot.to_bind_group_entry()],
+            }),
+            pipeline: device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("horizontal_pass_pipeline"),
+                entry_point: Some("horizontal_pass"),
+                layout: Some(&pipeline_layout),
+                module: &shader_module,
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                cache: None,
+            }),
+        }
+    }
+    fn create_forest_bind_group(
+        device: &wgpu::Device,
+        slot: &ForestBufferSlot,
+        layout: &wgpu::BindGroupLayout,
+        forest: &RandomForest,
+    ) -> wgpu::BindGroup {
+        let forest_buffer = slot.create_buffer(device, forest);
+        device.create_bind_group(&wgpu::BindGroupDescriptor{
+            label: Some("forest_group"),
+            layout,
+            entries: &[slot.to_bind_group_entry(&forest_buffer)],
+        })
+    }
+    /// Replaces the forest used by a pipeline created with [`ForestEvaluation::NodeBuffer`], without
+    /// recompiling the shader. The new forest must have the same number of classes
+    pub fn upload_forest(&mut self, forest: &RandomForest) -> Result<(), String> {
+        let Some(slot) = &self.forest_buffer_slot else {
+            return Err("Pipeline has its forest inlined in the shader; create a new pipeline instead".into())
+        };
+        if forest.num_classes() != slot.num_classes() {
+            return Err(format!(
+                "Pipeline was built for {} classes, but forest has {}", slot.num_classes(), forest.num_classes()
+            ))
+        }
+        if forest.feature_spec().is_some_and(|spec| *spec != self.feature_spec) {
+            return Err("Forest was trained on different features than the pipeline computes".into())
         }
+        if forest.highest_feature_idx() >= self.feature_spec.len() {
+            return Err(format!(
+                "Forest uses feature {}, but pipeline only computes {} features",
+                forest.highest_feature_idx(), self.feature_spec.len(),
+            ))
+        }
+        let layout = self.pipeline.get_bind_group_layout(Self::FOREST_GROUP.into());
+        self.forest_bind_group = Some(Self::create_forest_bind_group(&self.device, slot, &layout, forest));
+        Ok(())
+    }
+    /// The `(filter index, channel)` pairs that the shader computes. Filters and channels that no
+    /// used feature needs are left out of the shader entirely
+    pub fn used_responses(&self) -> &BTreeSet<(usize, usize)>{
+        &self.used_responses
     }
     pub fn process(
         &self,
         img: &image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
-    ) -> Result<Vec<[f32; 4]>, String> {
+    ) -> Result<PipelineOutput, String> {
         {
-            let expected_extent = self.output_buffer_slot.img_extent;
+            let expected_extent = self.img_extent;
             let found_extent = img.extent();
             if found_extent != expected_extent {
                 return Err(format!(
@@ -171,20 +749,32 @@ impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
         let input_texture = self.input_texture_slot.create_texture(&self.device, img.extent());
         input_texture.write_texture(&self.queue, img);
 
-        //FIXME: hardcoding vec4, expecting it to always be a rgba image
-        let output_buffer = self.output_buffer_slot.create_output_buffer(&self.device);
-        let download_buffer = DownloadBuffer::new_for_predictions(img, &self.device, Some("read_buffer"));
+        let output_buffers: Vec<(wgpu::Buffer, DownloadBuffer<u8>)> = self.output_slots.iter()
+            .map(|(_, slot)| {
+                let num_bytes = slot.output_buffer_size() as usize;
+                (slot.create_output_buffer(&self.device), DownloadBuffer::new(&self.device, Some("read_buffer"), num_bytes))
+            })
+            .collect();
 
+        let mut inout_entries = vec![input_texture.to_bind_group_entry()];
+        for ((_, slot), (buffer, _)) in self.output_slots.iter().zip(&output_buffers){
+            inout_entries.push(wgpu::BindGroupEntry{
+                binding: slot.binding().into(),
+                resource: buffer.as_entire_binding(),
+            });
+        }
         let inout_binding_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("binding_for_filter_pipeline"),
             layout: &self.pipeline.get_bind_group_layout(Self::INOUT_GROUP.into()),
-            entries: &[
-                input_texture.to_bind_group_entry(),
-                wgpu::BindGroupEntry{
-                    binding: self.output_buffer_slot.binding.into(),
-                    resource: output_buffer.as_entire_binding(),
-                },
-            ],
+            entries: &inout_entries,
+        });
+
+        let horizontal_pass_inout_group = self.horizontal_pass.as_ref().map(|horizontal_pass| {
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("horizontal_pass_inout_group"),
+                layout: &horizontal_pass.pipeline.get_bind_group_layout(Self::INOUT_GROUP.into()),
+                entries: &[input_texture.to_bind_group_entry()],
+            })
         });
 
         let mut command_encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -196,24 +786,690 @@ impl<const KSIDE: usize> FeatureExtractorPipeline<KSIDE> {
                 label: Some("my_compute_pass"),
                 timestamp_writes: None, 
             });
+            let (x, y, z) = img.extent().num_dispatch_work_groups(&self.workgroup_size);
+            if let Some((horizontal_pass, inout_group)) = self.horizontal_pass.as_ref().zip(horizontal_pass_inout_group.as_ref()){
+                compute_pass.set_pipeline(&horizontal_pass.pipeline);
+                compute_pass.set_bind_group(Self::INOUT_GROUP.into(), inout_group, &[]);
+                compute_pass.set_bind_group(Self::KERNELS_GROUP.into(), &horizontal_pass.kernels_bind_group, &[]);
+                compute_pass.dispatch_workgroups(x, y, z);
+            }
             compute_pass.set_pipeline(&self.pipeline);
             compute_pass.set_bind_group(Self::INOUT_GROUP.into(), &inout_binding_group, &[]);
             compute_pass.set_bind_group(Self::KERNELS_GROUP.into(), &self.kernels_bind_group, &[]);
-            let (x, y, z) = img.extent().num_dispatch_work_groups(&self.workgroup_size);
+            if let Some(forest_bind_group) = &self.forest_bind_group {
+                compute_pass.set_bind_group(Self::FOREST_GROUP.into(), forest_bind_group, &[]);
+            }
             println!("Dispatch workgrounps: x: {x} y: {y} z: {z}");
             compute_pass.dispatch_workgroups(x, y, z);
             // drop(compute_pass); //FIXME?: forcing pass to end here, I hope
         }
 
-        download_buffer.issue_copy_from(&output_buffer, &mut command_encoder);
+        for (buffer, download_buffer) in &output_buffers{
+            download_buffer.issue_copy_from(buffer, &mut command_encoder);
+        }
 
         self.queue.submit(Some(command_encoder.finish()));
 
-        let predictions_reader = download_buffer.map_async();
+        let readers: Vec<_> = output_buffers.into_iter()
+            .map(|(_, download_buffer)| download_buffer.map_async())
+            .collect();
 
         self.device.poll(wgpu::PollType::wait()).unwrap(); //FIXME: do we even need this anymore with DownloadBuffer's channel?
 
-        let (predictions, _download_buffer) = predictions_reader.readback();
-        Ok(predictions)
+        let num_pixels = (img.width() * img.height()) as usize;
+        let mut output = PipelineOutput{predictions: None, uncertainty: None, probabilities: None, labels: None, features: None};
+        for ((kind, _), reader) in self.output_slots.iter().zip(readers){
+            // every output is decoded straight from the mapped buffer, whose alignment makes the casts safe
+            reader.read_mapped(|bytes| match kind {
+                OutputKind::Colors => output.predictions = Some(copy_bytes(bytes, "colors from GPU to CPU")),
+                OutputKind::Features => output.features = Some(copy_bytes(bytes, "features from GPU to CPU")),
+                OutputKind::Uncertainty(_) => output.uncertainty = Some(copy_bytes(bytes, "uncertainty from GPU to CPU")),
+                OutputKind::Probabilities(ProbabilityOutput{format, layout}) => output.probabilities = Some(
+                    ProbabilityMap::from_bytes(bytes, *format, *layout, num_pixels, self.num_classes)
+                ),
+                OutputKind::Labels(format) => {
+                    output.labels = Some(LabelImage::unpack(bytemuck::cast_slice(bytes), *format, num_pixels));
+                },
+            });
+        }
+        Ok(output)
+    }
+}
+
+/// [`FeatureExtractorPipeline::new`] with the workgroup size all tests use
+#[cfg(test)]
+fn test_pipeline(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    kernels: Vec<Box<dyn Filter>>,
+    forest: &RandomForest,
+    options: PipelineOptions,
+    img_extent: wgpu::Extent3d,
+) -> Result<FeatureExtractorPipeline, String>{
+    FeatureExtractorPipeline::new(device.clone(), queue.clone(), WorkgroupSize{x: 8, y: 8, z: 1}, kernels, forest, options, img_extent)
+}
+
+#[cfg(test)]
+const TEST_COLORS: [[u8; 3]; 10] = [
+    [20, 20, 20], [120, 20, 220], [120, 120, 20], [220, 120, 120], [20, 220, 220],
+    [220, 20, 20], [20, 120, 20], [220, 220, 120], [70, 170, 70], [170, 70, 170],
+];
+
+/// Boxed [`GaussianBlur`]s at `sigmas`, the filters most tests use
+#[cfg(test)]
+fn gaussians(sigmas: &[f32]) -> Vec<Box<dyn Filter>>{
+    sigmas.iter().map(|sigma| Box::new(GaussianBlur::new(*sigma)) as Box<dyn Filter>).collect()
+}
+
+/// The features of every pixel of a constant image of `color`. Each kernel's response is just the
+/// pixel value scaled by the sum of the kernel, and the filters compute their features from those
+#[cfg(test)]
+fn constant_image_features(color: [u8; 3], kernels: &[Box<dyn Filter>]) -> Vec<f32>{
+    kernels.iter()
+        .flat_map(|kernel| color.into_iter().flat_map(|channel| {
+            let responses: Vec<f32> = kernel.kernels().iter()
+                .map(|separable| f32::from(channel) * separable.x.iter().sum::<f32>() * separable.y.iter().sum::<f32>())
+                .collect();
+            kernel.components_from_responses(&responses)
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+fn assert_matches_cpu_on_constant_images(
+    pipeline: &FeatureExtractorPipeline, forest: &RandomForest, kernels: &[Box<dyn Filter>]
+){
+    for color in TEST_COLORS{
+        let image = image::ImageBuffer::from_pixel(4, 4, image::Rgba([color[0], color[1], color[2], 255]));
+        let features = constant_image_features(color, kernels);
+        let expected_color = Palette::for_classes(forest.classes()).color_f32(forest.predict(&features));
+
+        let output = pipeline.process(&image).unwrap().predictions.unwrap();
+        assert!(output.iter().all(|pixel| *pixel == expected_color), "Bad output for {color:?}: {:?}", output[0]);
+    }
+}
+
+#[test]
+fn test_forest_shader_matches_cpu_evaluation(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    // the forest only splits on some channels of the first two kernels, so the inlined shader prunes the rest
+    let forest = crate::decision_tree::three_class_forest();
+    let kernels = gaussians(&[1.0, 2.0, 3.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    for forest_evaluation in [ForestEvaluation::Inlined, ForestEvaluation::NodeBuffer]{
+        let options = PipelineOptions{forest_evaluation, ..Default::default()};
+        let pipeline = test_pipeline(
+            &device, &queue, kernels.clone(), &forest, options, extent
+        ).unwrap();
+        assert_matches_cpu_on_constant_images(&pipeline, &forest, &kernels);
+    }
+}
+
+#[test]
+fn test_unused_responses_are_pruned(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    // features 0, 2 and 4 are channels 0 and 2 of the first kernel and channel 1 of the second
+    let forest = crate::decision_tree::three_class_forest();
+    let kernels = gaussians(&[1.0, 2.0, 3.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    let pruned = BTreeSet::from([(0, 0), (0, 2), (1, 1)]);
+    for (forest_evaluation, expected) in [
+        (ForestEvaluation::Inlined, pruned.clone()),
+        (ForestEvaluation::NodeBuffer, (0..3).flat_map(|f_idx| (0..3).map(move |channel| (f_idx, channel))).collect()),
+    ]{
+        let options = PipelineOptions{forest_evaluation, ..Default::default()};
+        let pipeline = test_pipeline(&device, &queue, kernels.clone(), &forest, options, extent).unwrap();
+        assert_eq!(pipeline.used_responses(), &expected, "{forest_evaluation:?}");
+    }
+
+    // the shader neither convolves the unused kernel nor the unused channels of the used ones
+    for convolution in [Convolution::Direct, Convolution::Separable]{
+        let slot = KernelsInBuffSlot::new(&device, "kernels".to_owned(), Group(0), Binding(0), kernels.clone(), convolution);
+        let mut code = String::new();
+        slot.write_wgsl_feature_calcs(&mut code, &pruned, "responses").unwrap();
+        for used in ["response_0[0] +=", "response_0[2] +=", "response_1[1] +=", "let feature_0_0 =", "let feature_1_0 ="]{
+            assert!(code.contains(used), "{convolution:?} lacks '{used}':{code}");
+        }
+        for unused in ["response_0[1]", "response_1[0]", "response_1[2]", "response_2", "feature_2_0"]{
+            assert!(!code.contains(unused), "{convolution:?} computes '{unused}':{code}");
+        }
+        let mut horizontal_code = String::new();
+        slot.write_wgsl_horizontal_pass(&mut horizontal_code, &pruned, "responses").unwrap();
+        assert_eq!(horizontal_code.matches("textureStore").count(), 2, "{horizontal_code}");
+    }
+}
+
+#[test]
+fn test_uploading_forest_to_node_buffer_pipeline(){
+    use crate::decision_tree::DecisionTree;
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let forest = crate::decision_tree::three_class_forest();
+    let kernels = gaussians(&[1.0, 2.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    let options = PipelineOptions{forest_evaluation: ForestEvaluation::NodeBuffer, ..Default::default()};
+    let mut pipeline = test_pipeline(
+        &device, &queue, kernels.clone(), &forest, options, extent
+    ).unwrap();
+
+    let retrained_forest = RandomForest::from_trees(vec![DecisionTree::parse(r#"
+        digraph Tree {
+            0 [label="node #0\nx[1] <= 100.0\nsamples = 20\nvalue = [10, 0, 10]\nclass = 0"] ;
+            1 [label="node #1\nsamples = 10\nvalue = [0, 0, 10]\nclass = 2"] ;
+            0 -> 1 [labeldistance=2.5, labelangle=45, headlabel="True"] ;
+            2 [label="node #2\nsamples = 10\nvalue = [10, 0, 0]\nclass = 0"] ;
+            0 -> 2 [labeldistance=2.5, labelangle=-45, headlabel="False"] ;
+        }
+    "#).unwrap()]).unwrap();
+    pipeline.upload_forest(&retrained_forest).unwrap();
+    assert_matches_cpu_on_constant_images(&pipeline, &retrained_forest, &kernels);
+
+    let two_class_forest = RandomForest::from_trees(vec![
+        DecisionTree::parse(crate::decision_tree::TWO_LEVEL_TREE_DOT).unwrap()
+    ]).unwrap();
+    assert!(pipeline.upload_forest(&two_class_forest).is_err());
+}
+
+#[test]
+fn test_argmax_for_any_class_count(){
+    use crate::decision_tree::{DecisionTree, TrainingParams};
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let kernels = gaussians(&[1.0, 2.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    let features: Vec<f32> = TEST_COLORS.iter().flat_map(|color| constant_image_features(*color, &kernels)).collect();
+    for num_classes in [2, 3, 8]{
+        let labels: Vec<usize> = (0..TEST_COLORS.len()).map(|color_idx| color_idx % num_classes).collect();
+        let params = TrainingParams{num_trees: 5, bootstrap: false, max_features: Some(6), ..Default::default()};
+        let forest = RandomForest::train(&features, &labels, &params).unwrap();
+        assert_eq!(forest.num_classes(), num_classes);
+        for forest_evaluation in [ForestEvaluation::Inlined, ForestEvaluation::NodeBuffer]{
+            let options = PipelineOptions{forest_evaluation, ..Default::default()};
+            let pipeline = test_pipeline(
+                &device, &queue, kernels.clone(), &forest, options, extent
+            ).unwrap();
+            assert_matches_cpu_on_constant_images(&pipeline, &forest, &kernels);
+        }
+    }
+
+    // two trees that split the vote evenly between classes 6 and 3: scikit picks the lower index
+    let tied_forest = RandomForest::from_trees(vec![
+        DecisionTree::parse(r#"digraph Tree { 0 [label="value = [0, 0, 0, 0, 0, 0, 1, 0]\nclass = 6"] ; }"#).unwrap(),
+        DecisionTree::parse(r#"digraph Tree { 0 [label="value = [0, 0, 0, 1, 0, 0, 0, 0]\nclass = 3"] ; }"#).unwrap(),
+    ]).unwrap();
+    assert_eq!(tied_forest.predict(&[0.0; 6]), 3);
+    for voting in [VotingMode::Hard, VotingMode::Soft]{
+        let options = PipelineOptions{voting, ..Default::default()};
+        let pipeline = test_pipeline(
+            &device, &queue, kernels.clone(), &tied_forest, options, extent
+        ).unwrap();
+        let output = pipeline.process(&image::ImageBuffer::from_pixel(4, 4, image::Rgba([0, 0, 0, 255]))).unwrap().predictions.unwrap();
+        assert!(output.iter().all(|pixel| *pixel == Palette::for_classes(tied_forest.classes()).color_f32(3)));
+    }
+}
+
+#[test]
+fn test_feature_spec_maps_features_to_kernels(){
+    use crate::feature_spec::Feature;
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let kernels = gaussians(&[1.0, 2.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    let smoothing = |sigma: f32, channel: usize| Feature{filter: FilterKind::GaussianSmoothing, sigma, inner_sigma: None, channel, component: 0};
+
+    // features in an order unrelated to the kernels', with feature 2 only looking at the red channel
+    let spec = FeatureSpec::new(vec![
+        smoothing(2.0, 2), smoothing(1.0, 1), smoothing(2.0, 0), smoothing(1.0, 0), smoothing(2.0, 1),
+    ]);
+    let forest = crate::decision_tree::three_class_forest().with_feature_spec(spec.clone()).unwrap();
+    for forest_evaluation in [ForestEvaluation::Inlined, ForestEvaluation::NodeBuffer]{
+        let options = PipelineOptions{forest_evaluation, ..Default::default()};
+        let pipeline = test_pipeline(
+            &device, &queue, kernels.clone(), &forest, options, extent
+        ).unwrap();
+        for color in TEST_COLORS{
+            let default_features = constant_image_features(color, &kernels);
+            let features: Vec<f32> = spec.features().iter()
+                .map(|feature| {
+                    let k_idx = kernels.iter().position(|kernel| kernel.sigma() == feature.sigma).unwrap();
+                    default_features[k_idx * 3 + feature.channel]
+                })
+                .collect();
+            let image = image::ImageBuffer::from_pixel(4, 4, image::Rgba([color[0], color[1], color[2], 255]));
+            let output = pipeline.process(&image).unwrap().predictions.unwrap();
+            assert!(output.iter().all(|pixel| *pixel == Palette::for_classes(forest.classes()).color_f32(forest.predict(&features))));
+        }
+    }
+
+    let unavailable_spec = FeatureSpec::new(vec![smoothing(1.0, 0), smoothing(1.0, 1), smoothing(5.0, 0), smoothing(1.0, 2), smoothing(2.0, 1)]);
+    let forest = crate::decision_tree::three_class_forest().with_feature_spec(unavailable_spec).unwrap();
+    let err = test_pipeline(
+        &device, &queue, kernels, &forest, PipelineOptions::default(), extent
+    ).err().unwrap();
+    assert!(err.contains("feature 2"), "{err}");
+}
+
+#[test]
+fn test_uncertainty_matches_cpu(){
+    use crate::decision_tree::TrainingParams;
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let kernels = gaussians(&[1.0, 2.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    let features: Vec<f32> = TEST_COLORS.iter().flat_map(|color| constant_image_features(*color, &kernels)).collect();
+    let labels: Vec<usize> = (0..TEST_COLORS.len()).map(|color_idx| color_idx % 3).collect();
+    // shallow trees that each look at different features disagree, so the votes are not all unanimous.
+    // Without bootstrapping, no split threshold can land right on one of the test colors' features
+    let params = TrainingParams{num_trees: 7, max_depth: Some(2), max_features: Some(2), bootstrap: false, ..Default::default()};
+    let forest = RandomForest::train(&features, &labels, &params).unwrap();
+
+    let mut saw_undecided_vote = false;
+    for uncertainty in [Uncertainty::Margin, Uncertainty::Entropy]{
+        for voting in [VotingMode::Hard, VotingMode::Soft]{
+            let options = PipelineOptions{voting, uncertainty: Some(uncertainty), ..Default::default()};
+            let pipeline = test_pipeline(
+                &device, &queue, kernels.clone(), &forest, options, extent
+            ).unwrap();
+            for (color, features) in TEST_COLORS.iter().zip(features.chunks(kernels.len() * 3)){
+                let expected = uncertainty.of(&forest.class_scores(features, voting));
+                saw_undecided_vote |= expected > 0.1;
+                let image = image::ImageBuffer::from_pixel(4, 4, image::Rgba([color[0], color[1], color[2], 255]));
+                let output = pipeline.process(&image).unwrap().uncertainty.unwrap();
+                assert_eq!(output.len(), 16);
+                assert!(
+                    output.iter().all(|value| (value - expected).abs() < 1e-5),
+                    "{uncertainty:?} of {color:?} with {voting:?} voting: expected {expected}, got {}", output[0]
+                );
+            }
+        }
+    }
+
+    assert!(saw_undecided_vote);
+
+    let pipeline = test_pipeline(
+        &device, &queue, kernels, &forest, PipelineOptions::default(), extent
+    ).unwrap();
+    assert!(pipeline.process(&image::ImageBuffer::from_pixel(4, 4, image::Rgba([0, 0, 0, 255]))).unwrap().uncertainty.is_none());
+}
+
+#[test]
+fn test_probability_maps_match_cpu(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let forest = crate::decision_tree::three_class_forest();
+    let kernels = gaussians(&[1.0, 2.0, 3.0]);
+    // 15 pixels of 3 classes don't fill the last word of the packed formats
+    let extent = wgpu::Extent3d{width: 5, height: 3, depth_or_array_layers: 1};
+    let num_pixels = 15;
+    // the normalized formats are off by up to half a step
+    let formats = [
+        (ProbabilityFormat::F32, 1e-6),
+        (ProbabilityFormat::Unorm8, 0.5 / 255.0 + 1e-6),
+        (ProbabilityFormat::Unorm16, 0.5 / 65535.0 + 1e-6),
+    ];
+    for (format, tolerance) in formats{
+        for layout in [ChannelLayout::ChannelLast, ChannelLayout::ChannelFirst]{
+            let options = PipelineOptions{probabilities: Some(ProbabilityOutput{format, layout}), ..Default::default()};
+            let pipeline = test_pipeline(
+                &device, &queue, kernels.clone(), &forest, options, extent
+            ).unwrap();
+            for color in TEST_COLORS{
+                let expected = forest.predict_proba(&constant_image_features(color, &kernels));
+                let image = image::ImageBuffer::from_pixel(5, 3, image::Rgba([color[0], color[1], color[2], 255]));
+                let probabilities = pipeline.process(&image).unwrap().probabilities.unwrap();
+                let num_values = match &probabilities.values {
+                    ProbabilityValues::F32(values) => values.len(),
+                    ProbabilityValues::Unorm8(values) => values.len(),
+                    ProbabilityValues::Unorm16(values) => values.len(),
+                };
+                assert_eq!(num_values, num_pixels * 3);
+                for pixel_idx in 0..num_pixels{
+                    for (class_idx, expected) in expected.iter().enumerate(){
+                        let found = probabilities.get(pixel_idx, class_idx);
+                        assert!(
+                            (found - expected).abs() <= tolerance,
+                            "{format:?} {layout:?} class {class_idx} of {color:?}: expected {expected}, got {found}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_outputs_to_npy(){
+    use crate::npy::{Endianness, Order};
+
+    fn round_trip<T: NpyElement + PartialEq + std::fmt::Debug>(array: &NpyArray<T>) -> NpyArray<T>{
+        let mut bytes = vec![];
+        array.write_to(&mut bytes, Endianness::Little, Order::C).unwrap();
+        NpyArray::read_from(bytes.as_slice()).unwrap()
+    }
+    // 3x2 pixels with 2 classes, the first class getting pixel_idx / 10
+    let channel_last = ProbabilityMap{
+        values: ProbabilityValues::F32((0..6).flat_map(|pixel_idx| [pixel_idx as f32 / 10.0, 1.0 - pixel_idx as f32 / 10.0]).collect()),
+        layout: ChannelLayout::ChannelLast, num_pixels: 6, num_classes: 2,
+    };
+    let channel_first = ProbabilityMap{
+        values: ProbabilityValues::Unorm8((0..6).map(|pixel_idx| pixel_idx * 51).chain((0..6).map(|pixel_idx| 255 - pixel_idx * 51)).collect()),
+        layout: ChannelLayout::ChannelFirst, num_pixels: 6, num_classes: 2,
+    };
+    for map in [channel_last, channel_first]{
+        let array = map.to_npy(3, 2).unwrap();
+        let read = round_trip(&array);
+        assert_eq!(read, array);
+        for (y, x, class_idx) in (0..2).flat_map(|y| (0..3).flat_map(move |x| (0..2).map(move |class_idx| (y, x, class_idx)))){
+            let value_idx = match map.layout {
+                ChannelLayout::ChannelLast => (y * 3 + x) * 2 + class_idx,
+                ChannelLayout::ChannelFirst => (class_idx * 2 + y) * 3 + x,
+            };
+            assert_eq!(read.data()[value_idx], map.get(y * 3 + x, class_idx), "{:?} at ({x}, {y})", map.layout);
+        }
+    }
+    assert_eq!(round_trip(&ProbabilityMap::to_npy(&ProbabilityMap{
+        values: ProbabilityValues::Unorm16(vec![0, 65535]), layout: ChannelLayout::ChannelFirst, num_pixels: 1, num_classes: 2,
+    }, 1, 1).unwrap()).shape(), &[2, 1, 1]);
+
+    let labels = LabelImage::U16(vec![0, 1, 300, 2, 1, 0]);
+    let array = labels.to_npy::<u16>(3, 2).unwrap();
+    assert_eq!(array.shape(), &[2, 3]);
+    assert_eq!(round_trip(&array).data(), &[0, 1, 300, 2, 1, 0]);
+    assert!(labels.to_npy::<u8>(3, 2).is_err());
+    assert_eq!(round_trip(&LabelImage::U8(vec![3, 1]).to_npy::<u8>(1, 2).unwrap()).shape(), &[2, 1]);
+
+    let output = PipelineOutput{
+        predictions: None, uncertainty: None, probabilities: None, labels: None,
+        features: Some((0..24).map(|value| value as f32).collect()),
+    };
+    let features = round_trip(&output.features_to_npy(3, 2).unwrap());
+    assert_eq!(features.shape(), &[2, 3, 4]);
+    assert_eq!(features.data(), output.features.as_ref().unwrap().as_slice());
+    assert!(PipelineOutput{features: None, ..output}.features_to_npy(3, 2).is_err());
+}
+
+#[test]
+fn test_label_output(){
+    assert_eq!(LabelImage::unpack(&[0x0403_0201, 0x0000_0605], LabelFormat::U8, 6), LabelImage::U8(vec![1, 2, 3, 4, 5, 6]));
+    assert_eq!(LabelImage::unpack(&[0x0002_0001, 0x0000_0103], LabelFormat::U16, 3), LabelImage::U16(vec![1, 2, 259]));
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let forest = crate::decision_tree::three_class_forest();
+    let kernels = gaussians(&[1.0, 2.0, 3.0]);
+    // 15 labels don't fill the last word
+    let extent = wgpu::Extent3d{width: 5, height: 3, depth_or_array_layers: 1};
+    for format in [LabelFormat::U8, LabelFormat::U16]{
+        let options = PipelineOptions{labels: Some(format), colors: false, ..Default::default()};
+        let pipeline = test_pipeline(
+            &device, &queue, kernels.clone(), &forest, options, extent
+        ).unwrap();
+        for color in TEST_COLORS{
+            let expected = forest.predict(&constant_image_features(color, &kernels));
+            let image = image::ImageBuffer::from_pixel(5, 3, image::Rgba([color[0], color[1], color[2], 255]));
+            let output = pipeline.process(&image).unwrap();
+            assert!(output.predictions.is_none());
+            let labels = output.labels.unwrap();
+            assert_eq!(labels.len(), 15);
+            assert!((0..15).all(|pixel_idx| labels.get(pixel_idx) == expected), "Bad labels for {color:?}: {labels:?}");
+        }
+    }
+
+    let options = PipelineOptions{colors: false, ..Default::default()};
+    let err = test_pipeline(
+        &device, &queue, kernels, &forest, options, extent
+    ).err().unwrap();
+    assert!(err.contains("no outputs"), "{err}");
+}
+
+#[test]
+fn test_palette_overlay_matches_cpu(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let forest = crate::decision_tree::three_class_forest();
+    let kernels = gaussians(&[1.0, 2.0, 3.0]);
+    let extent = wgpu::Extent3d{width: 4, height: 4, depth_or_array_layers: 1};
+    let palette = Palette::new(vec![[255, 0, 0, 255], [0, 255, 0, 128], [0, 0, 255, 0]]);
+    for overlay_opacity in [None, Some(1.0), Some(0.5)]{
+        let options = PipelineOptions{
+            labels: Some(LabelFormat::U8), palette: Some(palette.clone()), overlay_opacity, ..Default::default()
+        };
+        let pipeline = test_pipeline(
+            &device, &queue, kernels.clone(), &forest, options, extent
+        ).unwrap();
+        for color in TEST_COLORS{
+            let image = image::RgbaImage::from_pixel(4, 4, image::Rgba([color[0], color[1], color[2], 255]));
+            let output = pipeline.process(&image).unwrap();
+            let labels = output.labels.unwrap();
+            let expected = match overlay_opacity {
+                Some(opacity) => palette.overlay(&labels, &image, opacity),
+                None => palette.render(&labels, 4, 4),
+            };
+            for (gpu, cpu) in output.predictions.unwrap().iter().zip(expected.pixels()){
+                let gpu = gpu.map(|channel| (channel * 255.0).round() as u8);
+                assert!(
+                    gpu.iter().zip(cpu.0).all(|(gpu, cpu)| gpu.abs_diff(cpu) <= 1),
+                    "Bad color for {color:?} with opacity {overlay_opacity:?}: {gpu:?} vs {cpu:?}"
+                );
+            }
+        }
+    }
+
+    let options = PipelineOptions{palette: Some(Palette::generated(2)), ..Default::default()};
+    let err = test_pipeline(
+        &device, &queue, kernels, &forest, options, extent
+    ).err().unwrap();
+    assert!(err.contains("Palette has 2 colors"), "{err}");
+}
+
+
+#[test]
+fn test_separable_convolution_matches_2d(){
+    use nalgebra::Vector2;
+    use super::kernel::gaussian_blur::reflect_index;
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let forest = crate::decision_tree::three_class_forest();
+    let sigmas = [0.7, 1.6, 3.0];
+    let kernels = gaussians(&sigmas);
+    // odd sizes and a kernel wider than the image is tall, so the reflected borders get exercised
+    let image = image::RgbaImage::from_fn(13, 5, |x, y| {
+        image::Rgba([(x * 37 + y * 11) as u8, (x * y * 23) as u8, ((x + 3 * y) * 29 % 256) as u8, 255])
+    });
+    let (width, height) = (image.width() as i64, image.height() as i64);
+
+    // the 2D convolution with `kernel_at`, reflecting samples at the borders like the shader does
+    let mut expected: Vec<f32> = vec![];
+    for (x, y) in (0..height).flat_map(|y| (0..width).map(move |x| (x, y))){
+        for kernel in sigmas.map(GaussianBlur::new){
+            let radius = kernel.radius() as i64;
+            for channel in 0..3{
+                let mut response = 0.0;
+                for offset_y in -radius..=radius{
+                    for offset_x in -radius..=radius{
+                        let sample_x = reflect_index(x + offset_x, width) as u32;
+                        let sample_y = reflect_index(y + offset_y, height) as u32;
+                        let sample = f32::from(image.get_pixel(sample_x, sample_y)[channel]);
+                        response += sample * kernel.kernel_at(Vector2::new(offset_x, offset_y));
+                    }
+                }
+                expected.push(response);
+            }
+        }
+    }
+
+    for convolution in [Convolution::Direct, Convolution::Separable]{
+        let options = PipelineOptions{convolution, features: true, colors: false, ..Default::default()};
+        let pipeline = test_pipeline(
+            &device, &queue, kernels.clone(), &forest, options, image.extent()
+        ).unwrap();
+        let features = pipeline.process(&image).unwrap().features.unwrap();
+        assert_eq!(features.len(), expected.len());
+        for (value_idx, (gpu, cpu)) in features.iter().zip(&expected).enumerate(){
+            assert!((gpu - cpu).abs() < 1e-3, "{convolution:?} feature value {value_idx} is {gpu}, expected {cpu}");
+        }
+    }
+}
+
+/// Runs `filters` through the pipeline with both convolutions and checks every feature of `image`
+/// against [`apply_filter_on_cpu`](super::kernel::apply_filter_on_cpu)
+#[cfg(test)]
+fn assert_features_match_cpu(
+    device: &wgpu::Device, queue: &wgpu::Queue, filters: Vec<Box<dyn Filter>>, image: &image::RgbaImage, tolerance: f32
+){
+    use super::kernel::apply_filter_on_cpu;
+
+    // per filter and channel, one image per component
+    let responses: Vec<Vec<Vec<Vec<f32>>>> = filters.iter()
+        .map(|filter| (0..3).map(|channel| apply_filter_on_cpu(filter.as_ref(), image, channel)).collect())
+        .collect();
+    let num_pixels = (image.width() * image.height()) as usize;
+    let expected: Vec<f32> = (0..num_pixels)
+        .flat_map(|pixel_idx| responses.iter().flat_map(move |channels| {
+            channels.iter().flat_map(move |components| components.iter().map(move |component| component[pixel_idx]))
+        }))
+        .collect();
+
+    for convolution in [Convolution::Direct, Convolution::Separable]{
+        let options = PipelineOptions{convolution, features: true, colors: false, ..Default::default()};
+        let pipeline = test_pipeline(
+            device, queue, filters.clone(),
+            &crate::decision_tree::three_class_forest(), options, image.extent()
+        ).unwrap();
+        let features = pipeline.process(image).unwrap().features.unwrap();
+        assert_eq!(features.len(), expected.len());
+        for (value_idx, (gpu, cpu)) in features.iter().zip(&expected).enumerate(){
+            assert!((gpu - cpu).abs() < tolerance, "{convolution:?} feature value {value_idx} is {gpu}, expected {cpu}");
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_image() -> image::RgbaImage{
+    image::RgbaImage::from_fn(17, 11, |x, y| {
+        image::Rgba([(x * 37 + y * 11) as u8, (x * y * 23) as u8, ((x + 3 * y) * 29 % 256) as u8, 255])
+    })
+}
+
+#[test]
+fn test_gradient_magnitude_matches_cpu(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    use super::kernel::gaussian_gradient_magnitude::GaussianGradientMagnitude;
+
+    let filters: Vec<Box<dyn Filter>> = vec![
+        Box::new(GaussianBlur::new(1.0)),
+        Box::new(GaussianGradientMagnitude::new(0.7)),
+        Box::new(GaussianGradientMagnitude::new(1.6)),
+    ];
+    assert_features_match_cpu(&device, &queue, filters, &test_image(), 1e-3);
+}
+
+#[test]
+fn test_laplacian_matches_cpu(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    use super::kernel::laplacian_of_gaussian::LaplacianOfGaussian;
+
+    let filters: Vec<Box<dyn Filter>> = vec![
+        Box::new(LaplacianOfGaussian::new(0.7)),
+        Box::new(GaussianBlur::new(1.6)),
+        Box::new(LaplacianOfGaussian::new(1.6)),
+    ];
+    assert_features_match_cpu(&device, &queue, filters, &test_image(), 1e-3);
+}
+
+#[test]
+fn test_difference_of_gaussians_matches_cpu(){
+    use super::kernel::difference_of_gaussians::DifferenceOfGaussians;
+
+    let filters: Vec<Box<dyn Filter>> = vec![
+        Box::new(GaussianBlur::new(1.0)),
+        Box::new(DifferenceOfGaussians::with_sigmas(1.6, 1.0)),
+        Box::new(GaussianBlur::new(1.6)),
+        Box::new(DifferenceOfGaussians::new(3.0)),
+        // only the inner sigma tells this one apart from the one above
+        Box::new(DifferenceOfGaussians::new(1.6)),
+    ];
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    // the Difference of Gaussians with sigmas 1.6 and 1.0 reuses the responses of both Gaussians
+    let slot = KernelsInBuffSlot::new(&device, "kernels".to_owned(), Group(0), Binding(0), filters.clone(), Convolution::Separable);
+    let all_responses: BTreeSet<(usize, usize)> = (0..filters.len()).flat_map(|f_idx| (0..3).map(move |channel| (f_idx, channel))).collect();
+    assert_eq!(slot.used_x_kernels(&all_responses).len(), 5);
+    assert_features_match_cpu(&device, &queue, filters, &test_image(), 1e-3);
+}
+
+#[test]
+fn test_hessian_eigenvalues_match_cpu(){
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    use super::kernel::hessian_of_gaussian::HessianOfGaussianEigenvalues;
+
+    let filters: Vec<Box<dyn Filter>> = vec![
+        Box::new(HessianOfGaussianEigenvalues::new(0.7)),
+        Box::new(GaussianBlur::new(1.0)),
+        Box::new(HessianOfGaussianEigenvalues::new(1.6)),
+    ];
+    assert_features_match_cpu(&device, &queue, filters, &test_image(), 1e-3);
+}
+
+/// `bench/features.npy` holds the features that fastfilters computed for the labeled pixels of
+/// `c_cells_1.png` in `bench/bench.py`: Gaussian smoothings at 10 sigmas of each channel, sigma-major.
+/// The labels' coordinates weren't saved, so each reference sample is looked up among the features
+/// of the whole image by its first feature
+#[test]
+fn test_features_match_fastfilters(){
+    use crate::npy::NpyArray;
+
+    let Some((device, queue)) = test_device() else {
+        return
+    };
+    let reference = NpyArray::<f32>::load("bench/features.npy").unwrap();
+    let image = image::open("c_cells_1.png").unwrap().to_rgba8();
+    let kernels = gaussians(&[0.3, 0.7, 0.9, 1.0, 1.6, 3.5, 4.0, 5.0, 7.0, 10.0]);
+    let num_features = reference.shape()[1];
+    assert_eq!(num_features, kernels.len() * 3);
+
+    let options = PipelineOptions{features: true, colors: false, ..Default::default()};
+    let pipeline = test_pipeline(
+        &device, &queue, kernels, &crate::decision_tree::three_class_forest(), options, image.extent()
+    ).unwrap();
+    let features = pipeline.process(&image).unwrap().features.unwrap();
+    let pixels: Vec<&[f32]> = features.chunks(num_features).collect();
+    let mut by_first_feature: Vec<usize> = (0..pixels.len()).collect();
+    by_first_feature.sort_by(|a, b| pixels[*a][0].total_cmp(&pixels[*b][0]));
+
+    const TOLERANCE: f32 = 1e-3;
+    for (sample_idx, expected) in reference.data().chunks(num_features).enumerate(){
+        let candidates_start = by_first_feature.partition_point(|pixel_idx| pixels[*pixel_idx][0] < expected[0] - TOLERANCE);
+        let error = by_first_feature[candidates_start..].iter()
+            .take_while(|pixel_idx| pixels[**pixel_idx][0] <= expected[0] + TOLERANCE)
+            .map(|pixel_idx| {
+                pixels[*pixel_idx].iter().zip(expected).map(|(ours, theirs)| (ours - theirs).abs()).fold(0.0, f32::max)
+            })
+            .fold(f32::INFINITY, f32::min);
+        assert!(error < TOLERANCE, "No pixel has the features of reference sample {sample_idx}, closest is off by {error}");
     }
 }
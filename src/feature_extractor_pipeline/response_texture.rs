@@ -0,0 +1,88 @@
+use crate::util::{Binding, Group};
+
+/// The `rgba32float` array texture that the horizontal pass of a separable convolution writes its
+/// responses to, one layer per kernel, and that the vertical pass reads them back from
+pub struct ResponseTextureSlot {
+    name: String,
+    group: Group,
+    binding: Binding,
+    texture_view: wgpu::TextureView,
+}
+
+impl ResponseTextureSlot {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+
+    pub fn new(
+        device: &wgpu::Device,
+        name: String,
+        group: Group,
+        binding: Binding,
+        img_extent: wgpu::Extent3d,
+        num_layers: u32,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(&format!("response_texture__{name}")),
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            mip_level_count: 1,
+            sample_count: 1,
+            size: wgpu::Extent3d{
+                width: img_extent.width,
+                height: img_extent.height,
+                // on the GL backend, a texture with a single layer becomes a plain 2D texture that
+                // can't be viewed as an array, so there are always at least 2
+                depth_or_array_layers: num_layers.max(2),
+            },
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor{
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        Self{name, group, binding, texture_view}
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Declaration for the pass that fills the texture
+    pub fn to_wgsl_write_declaration(&self) -> String {
+        let Self{name, group, binding, ..} = self;
+        format!("@group({group}) @binding({binding}) var {name} : texture_storage_2d_array<rgba32float, write>;")
+    }
+    /// Declaration for the pass that reads the texture with `textureLoad`
+    pub fn to_wgsl_read_declaration(&self) -> String {
+        let Self{name, group, binding, ..} = self;
+        format!("@group({group}) @binding({binding}) var {name} : texture_2d_array<f32>;")
+    }
+    pub fn to_write_bind_group_layout_entry(&self) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding: self.binding.into(),
+            count: None,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: Self::FORMAT,
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+            },
+            visibility: wgpu::ShaderStages::COMPUTE,
+        }
+    }
+    pub fn to_read_bind_group_layout_entry(&self) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding: self.binding.into(),
+            count: None,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            visibility: wgpu::ShaderStages::COMPUTE,
+        }
+    }
+    pub fn to_bind_group_entry(&self) -> wgpu::BindGroupEntry<'_> {
+        wgpu::BindGroupEntry {
+            binding: self.binding.into(),
+            resource: wgpu::BindingResource::TextureView(&self.texture_view),
+        }
+    }
+}
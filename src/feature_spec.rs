@@ -0,0 +1,137 @@
+//! Describes what each feature index of a classifier stands for, so that the shader computes
+//! exactly the filter responses the forest was trained on, in the same order.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// A family of filters, named like in ilastik's feature selection
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FilterKind{
+    GaussianSmoothing,
+    GaussianGradientMagnitude,
+    LaplacianOfGaussian,
+    DifferenceOfGaussians,
+    /// Component 0 is the largest eigenvalue, component 1 the smallest
+    HessianOfGaussianEigenvalues,
+}
+
+impl FilterKind{
+    /// How many values the filter produces for each channel, e.g. one per eigenvalue
+    pub fn num_components(&self) -> usize{
+        match self{
+            Self::GaussianSmoothing => 1,
+            Self::GaussianGradientMagnitude => 1,
+            Self::LaplacianOfGaussian => 1,
+            Self::DifferenceOfGaussians => 1,
+            Self::HessianOfGaussianEigenvalues => 2,
+        }
+    }
+    pub fn name(&self) -> &'static str{
+        match self{
+            Self::GaussianSmoothing => "GaussianSmoothing",
+            Self::GaussianGradientMagnitude => "GaussianGradientMagnitude",
+            Self::LaplacianOfGaussian => "LaplacianOfGaussian",
+            Self::DifferenceOfGaussians => "DifferenceOfGaussians",
+            Self::HessianOfGaussianEigenvalues => "HessianOfGaussianEigenvalues",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self>{
+        match name{
+            "GaussianSmoothing" => Some(Self::GaussianSmoothing),
+            "GaussianGradientMagnitude" => Some(Self::GaussianGradientMagnitude),
+            "LaplacianOfGaussian" => Some(Self::LaplacianOfGaussian),
+            "DifferenceOfGaussians" => Some(Self::DifferenceOfGaussians),
+            "HessianOfGaussianEigenvalues" => Some(Self::HessianOfGaussianEigenvalues),
+            _ => None,
+        }
+    }
+}
+
+/// A single filter response: one component of one filter at one scale, applied to one input channel
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Feature{
+    pub filter: FilterKind,
+    pub sigma: f32,
+    /// The second scale of filters that have one, like the smaller Gaussian of a Difference of Gaussians
+    #[serde(default)]
+    pub inner_sigma: Option<f32>,
+    pub channel: usize,
+    /// Which of the filter's [`FilterKind::num_components`] values this is
+    pub component: usize,
+}
+
+impl Display for Feature{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self{filter, sigma, inner_sigma, channel, component} = self;
+        match inner_sigma{
+            Some(inner_sigma) => write!(f, "{} (sigma = {sigma}, inner sigma = {inner_sigma})", filter.name())?,
+            None => write!(f, "{} (sigma = {sigma})", filter.name())?,
+        }
+        write!(f, " of channel {channel}")?;
+        if filter.num_components() > 1{
+            write!(f, ", component {component}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The features a classifier was trained on. Feature index `i` of the forest is `features()[i]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeatureSpec{
+    features: Vec<Feature>,
+}
+
+impl FeatureSpec{
+    pub fn new(features: Vec<Feature>) -> Self{
+        Self{features}
+    }
+    /// The ordering ilastik uses: filters in the given order, then channels, then components
+    pub fn from_filters(filters: &[(FilterKind, f32)], num_channels: usize) -> Self{
+        let filters: Vec<_> = filters.iter().map(|(filter, sigma)| (*filter, *sigma, None)).collect();
+        Self::from_filters_with_inner_sigmas(&filters, num_channels)
+    }
+    /// Like [`Self::from_filters`], for filters that may also have an inner sigma
+    pub fn from_filters_with_inner_sigmas(filters: &[(FilterKind, f32, Option<f32>)], num_channels: usize) -> Self{
+        let features = filters.iter()
+            .flat_map(|(filter, sigma, inner_sigma)| (0..num_channels).flat_map(move |channel| {
+                (0..filter.num_components()).map(move |component| {
+                    Feature{filter: *filter, sigma: *sigma, inner_sigma: *inner_sigma, channel, component}
+                })
+            }))
+            .collect();
+        Self{features}
+    }
+    pub fn features(&self) -> &[Feature]{
+        &self.features
+    }
+    pub fn len(&self) -> usize{
+        self.features.len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.features.is_empty()
+    }
+    pub fn get(&self, feature_idx: usize) -> Option<&Feature>{
+        self.features.get(feature_idx)
+    }
+}
+
+#[test]
+fn test_feature_spec_ordering(){
+    let spec = FeatureSpec::from_filters(&[(FilterKind::GaussianSmoothing, 0.3), (FilterKind::GaussianSmoothing, 1.0)], 3);
+    assert_eq!(spec.len(), 6);
+    assert_eq!(spec.get(4), Some(&Feature{filter: FilterKind::GaussianSmoothing, sigma: 1.0, inner_sigma: None, channel: 1, component: 0}));
+    assert_eq!(spec.get(6), None);
+
+    let grayscale = FeatureSpec::from_filters(&[(FilterKind::GaussianSmoothing, 0.3), (FilterKind::GaussianSmoothing, 1.0)], 1);
+    assert_eq!(grayscale.get(1).unwrap().sigma, 1.0);
+    assert_eq!(grayscale.get(1).unwrap().channel, 0);
+
+    // every channel has all of a filter's components before the next channel
+    let hessian = FeatureSpec::from_filters(&[(FilterKind::HessianOfGaussianEigenvalues, 1.0)], 3);
+    assert_eq!(hessian.len(), 6);
+    assert_eq!(
+        hessian.get(3),
+        Some(&Feature{filter: FilterKind::HessianOfGaussianEigenvalues, sigma: 1.0, inner_sigma: None, channel: 1, component: 1})
+    );
+}
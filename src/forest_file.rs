@@ -0,0 +1,443 @@
+//! A versioned single-file format for a whole [`RandomForest`], so it doesn't have to be
+//! re-parsed from a directory of DOT files on every start.
+//!
+//! There is a compact binary variant and a human-readable JSON one; [`ForestFile::save`] and
+//! [`ForestFile::load`] pick between them by file extension. Both store the forest in its
+//! [`FlatForest`] form, so loading a saved forest gives back exactly the same nodes.
+//!
+//! The binary layout is, with all integers as little-endian `u32`:
+//! `[magic, version, num_classes, num_features, num_metadata_entries, (key, value)..., has_feature_spec,
+//! (filter, sigma, has_inner_sigma, inner_sigma, channel, component)..., (class_name, has_color, rgba)..., num_words,
+//! words...]` where strings are a byte length followed by UTF-8 bytes, sigmas are `f32`s, the spec has
+//! `num_features` entries if `has_feature_spec` is 1, `inner_sigma` is 0 unless `has_inner_sigma` is 1,
+//! there is a class entry for each of the `num_classes` classes with `rgba` as 4 bytes, and `words` is
+//! [`FlatForest::to_words`]
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{self as ah, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::decision_tree::{ClassInfo, FlatForest, FlatNode, RandomForest};
+use crate::feature_spec::{Feature, FeatureSpec, FilterKind};
+
+const MAGIC: &[u8; 8] = b"RFOREST\0";
+const JSON_FORMAT_NAME: &str = "gpu_filters.random_forest";
+/// Bumped whenever the layout of either variant changes. Files of other versions are rejected
+pub const FORMAT_VERSION: u32 = 4;
+
+pub struct ForestFile{
+    pub forest: RandomForest,
+    /// How many features the forest expects, which can be more than the trees actually look at
+    pub num_features: usize,
+    /// Free-form information about the forest, like how it was trained
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ForestFile{
+    pub fn new(forest: RandomForest) -> Self{
+        let num_features = match forest.feature_spec() {
+            Some(spec) => spec.len(),
+            None => forest.highest_feature_idx() + 1,
+        };
+        Self{forest, num_features, metadata: BTreeMap::new()}
+    }
+
+    /// Saves as JSON if `path` ends in `.json`, and in the binary format otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> ah::Result<()>{
+        let path = path.as_ref();
+        let contents = if is_json_path(path) { self.to_json().into_bytes() } else { self.to_bytes() };
+        std::fs::write(path, contents).context(format!("Writing forest to {}", path.display()))
+    }
+    pub fn load(path: impl AsRef<Path>) -> ah::Result<Self>{
+        let path = path.as_ref();
+        let contents = std::fs::read(path).context(format!("Reading forest from {}", path.display()))?;
+        let parsed = if is_json_path(path) {
+            std::str::from_utf8(&contents).context("Decoding JSON").and_then(Self::from_json)
+        } else {
+            Self::from_bytes(&contents)
+        };
+        parsed.context(format!("Loading forest from {}", path.display()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let words = self.forest.flatten().to_words();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + (words.len() + 6) * size_of::<u32>());
+        let push_u32 = |bytes: &mut Vec<u8>, value: usize| bytes.extend((value as u32).to_le_bytes());
+        bytes.extend(MAGIC);
+        push_u32(&mut bytes, FORMAT_VERSION as usize);
+        push_u32(&mut bytes, self.forest.num_classes());
+        push_u32(&mut bytes, self.num_features);
+        push_u32(&mut bytes, self.metadata.len());
+        for (key, value) in &self.metadata{
+            for s in [key, value]{
+                push_u32(&mut bytes, s.len());
+                bytes.extend(s.as_bytes());
+            }
+        }
+        push_u32(&mut bytes, usize::from(self.forest.feature_spec().is_some()));
+        for feature in self.forest.feature_spec().map(FeatureSpec::features).unwrap_or_default(){
+            let filter_name = feature.filter.name();
+            push_u32(&mut bytes, filter_name.len());
+            bytes.extend(filter_name.as_bytes());
+            bytes.extend(feature.sigma.to_le_bytes());
+            push_u32(&mut bytes, usize::from(feature.inner_sigma.is_some()));
+            bytes.extend(feature.inner_sigma.unwrap_or_default().to_le_bytes());
+            push_u32(&mut bytes, feature.channel);
+            push_u32(&mut bytes, feature.component);
+        }
+        for class in self.forest.classes(){
+            push_u32(&mut bytes, class.name.len());
+            bytes.extend(class.name.as_bytes());
+            push_u32(&mut bytes, usize::from(class.color.is_some()));
+            bytes.extend(class.color.unwrap_or_default());
+        }
+        push_u32(&mut bytes, words.len());
+        for word in words{
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> ah::Result<Self>{
+        let mut reader = ByteReader{bytes};
+        if reader.take(MAGIC.len())? != MAGIC{
+            ah::bail!("Not a forest file");
+        }
+        check_version(reader.u32()?)?;
+        let num_classes = reader.u32()? as usize;
+        let num_features = reader.u32()? as usize;
+        let mut metadata = BTreeMap::new();
+        for _ in 0..reader.u32()?{
+            let key = reader.string()?;
+            let value = reader.string()?;
+            metadata.insert(key, value);
+        }
+        let feature_spec = match reader.u32()? {
+            0 => None,
+            1 => {
+                let features = (0..num_features)
+                    .map(|_| {
+                        let filter_name = reader.string()?;
+                        let Some(filter) = FilterKind::from_name(&filter_name) else {
+                            ah::bail!("Unknown filter '{filter_name}'");
+                        };
+                        let sigma = f32::from_bits(reader.u32()?);
+                        let has_inner_sigma = reader.u32()?;
+                        let inner_sigma = f32::from_bits(reader.u32()?);
+                        let inner_sigma = match has_inner_sigma {
+                            0 => None,
+                            1 => Some(inner_sigma),
+                            other => ah::bail!("Bad inner sigma flag {other}"),
+                        };
+                        let channel = reader.u32()? as usize;
+                        let component = reader.u32()? as usize;
+                        Ok(Feature{filter, sigma, inner_sigma, channel, component})
+                    })
+                    .collect::<ah::Result<Vec<_>>>()?;
+                Some(FeatureSpec::new(features))
+            },
+            other => ah::bail!("Bad feature spec flag {other}"),
+        };
+        let classes = (0..num_classes)
+            .map(|index| {
+                let name = reader.string()?;
+                let has_color = reader.u32()?;
+                let rgba: [u8; 4] = reader.take(4)?.try_into().unwrap();
+                let color = match has_color {
+                    0 => None,
+                    1 => Some(rgba),
+                    other => ah::bail!("Bad class color flag {other}"),
+                };
+                Ok(ClassInfo{index, name, color})
+            })
+            .collect::<ah::Result<Vec<_>>>()?;
+        let num_words = reader.u32()? as usize;
+        let words = (0..num_words).map(|_| reader.u32()).collect::<ah::Result<Vec<_>>>()?;
+        if !reader.bytes.is_empty(){
+            ah::bail!("Found {} unexpected bytes after the forest", reader.bytes.len());
+        }
+        let flat = FlatForest::from_words(&words, num_classes)?;
+        Self::from_flat(&flat, num_features, feature_spec, classes, metadata)
+    }
+
+    pub fn to_json(&self) -> String{
+        let flat = self.forest.flatten();
+        let tree_ends = flat.roots.iter().skip(1).copied().chain([flat.nodes.len() as u32]);
+        let trees = flat.roots.iter().zip(tree_ends)
+            .map(|(root, end)| JsonTree{
+                nodes: flat.nodes[*root as usize..end as usize].iter()
+                    .map(|node| match node.feature_idx {
+                        FlatNode::LEAF => JsonNode::Leaf{
+                            class: node.gt_child as usize,
+                            probabilities: flat.leaf_probabilities[node.le_child as usize..][..flat.num_classes].to_vec(),
+                        },
+                        _ => JsonNode::Split{
+                            feature: node.feature_idx as usize,
+                            threshold: node.threshold,
+                            le_child: (node.le_child - root) as usize,
+                            gt_child: (node.gt_child - root) as usize,
+                        },
+                    })
+                    .collect(),
+            })
+            .collect();
+        let json_forest = JsonForest{
+            format: JSON_FORMAT_NAME.to_owned(),
+            version: FORMAT_VERSION,
+            num_classes: flat.num_classes,
+            num_features: self.num_features,
+            metadata: self.metadata.clone(),
+            feature_spec: self.forest.feature_spec().cloned(),
+            classes: self.forest.classes().iter()
+                .map(|class| JsonClass{name: class.name.clone(), color: class.color})
+                .collect(),
+            trees,
+        };
+        serde_json::to_string_pretty(&json_forest).unwrap()
+    }
+    pub fn from_json(json: &str) -> ah::Result<Self>{
+        // look at the version before anything else, so that other versions don't fail with confusing schema errors
+        let header: JsonHeader = serde_json::from_str(json).context("Parsing forest JSON header")?;
+        if header.format != JSON_FORMAT_NAME{
+            ah::bail!("Not a forest file: format is '{}'", header.format);
+        }
+        check_version(header.version)?;
+        let json_forest: JsonForest = serde_json::from_str(json).context("Parsing forest JSON")?;
+
+        let mut flat = FlatForest{
+            roots: vec![],
+            nodes: vec![],
+            leaf_probabilities: vec![],
+            num_classes: json_forest.num_classes,
+        };
+        for (tree_idx, tree) in json_forest.trees.iter().enumerate(){
+            let root = flat.nodes.len() as u32;
+            flat.roots.push(root);
+            for node in &tree.nodes{
+                let flat_node = match node {
+                    JsonNode::Leaf{class, probabilities} => {
+                        if probabilities.len() != json_forest.num_classes{
+                            ah::bail!(
+                                "Leaf in tree {tree_idx} has {} probabilities, expected {}",
+                                probabilities.len(), json_forest.num_classes
+                            );
+                        }
+                        let le_child = flat.leaf_probabilities.len() as u32;
+                        flat.leaf_probabilities.extend(probabilities);
+                        FlatNode{feature_idx: FlatNode::LEAF, threshold: 0.0, le_child, gt_child: *class as u32}
+                    },
+                    JsonNode::Split{feature, threshold, le_child, gt_child} => {
+                        // children are relative to the tree's root, and a malformed file could point anywhere
+                        let flat_child = |child: usize| {
+                            u32::try_from(child).ok().and_then(|child| root.checked_add(child))
+                                .with_context(|| format!("Child {child} of a split in tree {tree_idx} is out of range"))
+                        };
+                        FlatNode{
+                            feature_idx: u32::try_from(*feature)
+                                .with_context(|| format!("Feature {feature} of a split in tree {tree_idx} is out of range"))?,
+                            threshold: *threshold,
+                            le_child: flat_child(*le_child)?,
+                            gt_child: flat_child(*gt_child)?,
+                        }
+                    },
+                };
+                flat.nodes.push(flat_node);
+            }
+        }
+        let classes = if json_forest.classes.is_empty() {
+            (0..json_forest.num_classes).map(ClassInfo::unnamed).collect()
+        } else {
+            json_forest.classes.into_iter()
+                .enumerate()
+                .map(|(index, JsonClass{name, color})| ClassInfo{index, name, color})
+                .collect()
+        };
+        Self::from_flat(&flat, json_forest.num_features, json_forest.feature_spec, classes, json_forest.metadata)
+    }
+
+    fn from_flat(
+        flat: &FlatForest,
+        num_features: usize,
+        feature_spec: Option<FeatureSpec>,
+        classes: Vec<ClassInfo>,
+        metadata: BTreeMap<String, String>,
+    ) -> ah::Result<Self>{
+        let mut forest = RandomForest::from_flat(flat)?.with_class_table(classes)?;
+        if let Some(spec) = feature_spec{
+            if spec.len() != num_features{
+                ah::bail!("Feature spec describes {} features, but forest claims to have {num_features}", spec.len());
+            }
+            forest = forest.with_feature_spec(spec)?;
+        }
+        if forest.highest_feature_idx() >= num_features{
+            ah::bail!("Forest uses feature {} but claims to have {num_features} features", forest.highest_feature_idx());
+        }
+        Ok(Self{forest, num_features, metadata})
+    }
+}
+
+fn is_json_path(path: &Path) -> bool{
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+fn check_version(version: u32) -> ah::Result<()>{
+    if version != FORMAT_VERSION{
+        ah::bail!("Unsupported forest file version {version}, expected {FORMAT_VERSION}");
+    }
+    Ok(())
+}
+
+struct ByteReader<'a>{
+    bytes: &'a [u8],
+}
+
+impl ByteReader<'_>{
+    fn take(&mut self, len: usize) -> ah::Result<&[u8]>{
+        let Some((taken, rest)) = self.bytes.split_at_checked(len) else {
+            ah::bail!("Forest file is truncated");
+        };
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn u32(&mut self) -> ah::Result<u32>{
+        Ok(u32::from_le_bytes(self.take(size_of::<u32>())?.try_into().unwrap()))
+    }
+    fn string(&mut self) -> ah::Result<String>{
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).context("Decoding metadata string")
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonHeader{
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonForest{
+    format: String,
+    version: u32,
+    num_classes: usize,
+    num_features: usize,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    feature_spec: Option<FeatureSpec>,
+    /// One entry per class, in class index order. Missing classes are named after their index
+    #[serde(default)]
+    classes: Vec<JsonClass>,
+    trees: Vec<JsonTree>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonClass{
+    name: String,
+    #[serde(default)]
+    color: Option<[u8; 4]>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonTree{
+    /// In pre-order, with child indices relative to the tree
+    nodes: Vec<JsonNode>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonNode{
+    Split{
+        feature: usize,
+        threshold: f32,
+        le_child: usize,
+        gt_child: usize,
+    },
+    Leaf{
+        class: usize,
+        probabilities: Vec<f32>,
+    },
+}
+
+#[test]
+fn test_forest_file_round_trip(){
+    let mut file = ForestFile::new(crate::decision_tree::three_class_forest());
+    file.num_features = 8;
+    file.metadata.insert("trained_on".to_owned(), "c_cells_1.png".to_owned());
+    let flat = file.forest.flatten();
+
+    let from_bytes = ForestFile::from_bytes(&file.to_bytes()).unwrap();
+    let from_json = ForestFile::from_json(&file.to_json()).unwrap();
+    for loaded in [from_bytes, from_json]{
+        assert_eq!(loaded.forest.flatten(), flat);
+        assert_eq!(loaded.num_features, 8);
+        assert_eq!(loaded.metadata, file.metadata);
+        assert_eq!(loaded.forest.feature_spec(), None);
+    }
+
+    // the two Differences of Gaussians only differ in their inner sigma
+    let spec = FeatureSpec::from_filters_with_inner_sigmas(&[
+        (FilterKind::GaussianSmoothing, 0.7, None),
+        (FilterKind::DifferenceOfGaussians, 1.6, Some(1.0)),
+        (FilterKind::DifferenceOfGaussians, 1.6, Some(1.2)),
+    ], 3);
+    let file = ForestFile::new(crate::decision_tree::three_class_forest().with_feature_spec(spec.clone()).unwrap());
+    assert_eq!(file.num_features, 9);
+    let from_bytes = ForestFile::from_bytes(&file.to_bytes()).unwrap();
+    let from_json = ForestFile::from_json(&file.to_json()).unwrap();
+    for loaded in [from_bytes, from_json]{
+        assert_eq!(loaded.forest.feature_spec(), Some(&spec));
+    }
+
+    let classes = vec![
+        ClassInfo{index: 0, name: "background".to_owned(), color: None},
+        ClassInfo{index: 1, name: "membrane".to_owned(), color: Some([255, 0, 0, 255])},
+        ClassInfo{index: 2, name: "nucleus".to_owned(), color: Some([0, 0, 255, 128])},
+    ];
+    let file = ForestFile::new(crate::decision_tree::three_class_forest().with_class_table(classes.clone()).unwrap());
+    let from_bytes = ForestFile::from_bytes(&file.to_bytes()).unwrap();
+    let from_json = ForestFile::from_json(&file.to_json()).unwrap();
+    for loaded in [from_bytes, from_json]{
+        assert_eq!(loaded.forest.classes(), classes);
+    }
+
+    let path = std::env::temp_dir().join("test_forest_file_round_trip.json");
+    file.save(&path).unwrap();
+    assert_eq!(ForestFile::load(&path).unwrap().forest.flatten(), flat);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_forest_file_rejects_bad_files(){
+    let file = ForestFile::new(crate::decision_tree::three_class_forest());
+
+    let bytes = file.to_bytes();
+    for len in [4, 20, bytes.len() - 1]{
+        assert!(ForestFile::from_bytes(&bytes[..len]).is_err());
+    }
+    let mut future_bytes = bytes.clone();
+    future_bytes[MAGIC.len()..][..4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    let error = ForestFile::from_bytes(&future_bytes).err().unwrap();
+    assert!(error.to_string().contains("version"));
+
+    let json = file.to_json();
+    let future_json = json.replace(&format!("\"version\": {FORMAT_VERSION}"), &format!("\"version\": {}", FORMAT_VERSION + 1));
+    assert_ne!(json, future_json);
+    assert!(ForestFile::from_json(&future_json).err().unwrap().to_string().contains("version"));
+    assert!(ForestFile::from_json(&json[..json.len() / 2]).is_err());
+
+    // a split pointing back at the root would make the tree a cycle
+    let cyclic_json = json.replacen("\"le_child\": 1", "\"le_child\": 0", 1);
+    assert_ne!(json, cyclic_json);
+    assert!(ForestFile::from_json(&cyclic_json).is_err());
+
+    // children too far away for the flattened node indices must not overflow them, which takes a
+    // tree whose root isn't the first node
+    assert!(json.matches("\"nodes\"").count() > 1);
+    let (before_child, after_child) = json.rsplit_once("\"le_child\": ").unwrap();
+    let after_child = after_child.trim_start_matches(|c: char| c.is_ascii_digit());
+    for child in [u32::MAX as usize, u64::MAX as usize]{
+        let far_json = format!("{before_child}\"le_child\": {child}{after_child}");
+        assert!(ForestFile::from_json(&far_json).is_err(), "child {child}");
+    }
+}
@@ -2,68 +2,25 @@ pub mod feature_extractor_pipeline;
 pub mod util;
 pub mod wgsl;
 pub mod decision_tree;
+pub mod npy;
+pub mod forest_file;
+pub mod feature_spec;
+pub mod palette;
 
 use decision_tree::RandomForest;
-use feature_extractor_pipeline::{kernel::gaussian_blur::GaussianBlur, pipeline::FeatureExtractorPipeline};
-use pollster::FutureExt;
-use util::{timeit, ImageBufferExt, WorkgroupSize};
+use feature_extractor_pipeline::kernel::gaussian_blur::GaussianBlur;
+use feature_extractor_pipeline::kernel::Filter;
+use feature_extractor_pipeline::pipeline::{FeatureExtractorPipeline, PipelineOptions};
+use palette::Palette;
+use util::{request_device, timeit, ImageBufferExt, WorkgroupSize};
 use wgpu::Extent3d;
 
-fn make_pipeline<const KSIDE: usize>(
+fn make_pipeline(
     forest: &RandomForest,
-    kernels: Vec<GaussianBlur<KSIDE>>,
+    kernels: Vec<Box<dyn Filter>>,
     img_extent: Extent3d,
-) -> FeatureExtractorPipeline<KSIDE> {
-    // We first initialize an wgpu `Instance`, which contains any "global" state wgpu needs.
-    //
-    // This is what loads the vulkan/dx12/metal/opengl libraries.
-    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor{
-        // flags: wgpu::InstanceFlags::debugging(),
-        ..Default::default()
-    });
-
-    // We then create an `Adapter` which represents a physical gpu in the system. It allows
-    // us to query information about it and create a `Device` from it.
-    //
-    // This function is asynchronous in WebGPU, so request_adapter returns a future. On native/webgl
-    // the future resolves immediately, so we can block on it without harm.
-    let adapter = instance.request_adapter(
-            &wgpu::RequestAdapterOptions{
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                ..Default::default()
-            }
-        )
-        .block_on()
-        .expect("Failed to create adapter");
-
-    // Print out some basic information about the adapter.
-    println!("Running on Adapter: {:#?}", adapter.get_info());
-
-    // Check to see if the adapter supports compute shaders. While WebGPU guarantees support for
-    // compute shaders, wgpu supports a wider range of devices through the use of "downlevel" devices.
-    let downlevel_capabilities = adapter.get_downlevel_capabilities();
-    if !downlevel_capabilities
-        .flags
-        .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
-    {
-        panic!("Adapter does not support compute shaders");
-    }
-
-    // We then create a `Device` and a `Queue` from the `Adapter`.
-    //
-    // The `Device` is used to create and manage GPU resources.
-    // The `Queue` is a queue used to submit work for the GPU to process.
-    let (device, queue) = adapter.request_device(
-        &wgpu::DeviceDescriptor {
-            label: None,
-            required_features: wgpu::Features::empty(),
-            required_limits: wgpu::Limits::downlevel_defaults(),
-            memory_hints: wgpu::MemoryHints::MemoryUsage,
-            trace: wgpu::Trace::Off,
-        },
-    )
-    .block_on()
-    .expect("Failed to create device");
+) -> FeatureExtractorPipeline {
+    let (device, queue) = request_device().expect("Failed to create device");
 
     FeatureExtractorPipeline::new(
         device,
@@ -75,13 +32,12 @@ fn make_pipeline<const KSIDE: usize>(
         },
         kernels,
         forest,
+        PipelineOptions::default(),
         img_extent,
-    )
+    ).expect("Failed to create pipeline")
 }
 
 fn main() {
-    const KERNEL_SIDE: usize = 73;
-
     // wgpu uses `log` for all of our logging, so we initialize a logger with the `env_logger` crate.
     //
     // To change the log level, set the `RUST_LOG` environment variable. See the `env_logger`
@@ -91,6 +47,11 @@ fn main() {
 
     let forest: RandomForest = RandomForest::from_dir("./bench/out/benchmark_trees").unwrap();
     eprintln!("HIgest feat idx is {}", forest.highest_feature_idx());
+    let palette = Palette::for_classes(forest.classes());
+    for class in forest.classes(){
+        let [r, g, b, a] = palette.color(class.index);
+        eprintln!("Class {} '{}' is drawn as rgba({r}, {g}, {b}, {a})", class.index, class.name);
+    }
 
 
     // let img = image::io::Reader::open("./big.png").unwrap().decode().unwrap();
@@ -129,29 +90,30 @@ fn main() {
     let dims = image.dimensions();
     println!("Image has these dimensions: {:?} ", dims);
 
-    let kernels = vec![
+    let kernels: Vec<Box<dyn Filter>> = vec![
         // 0.3, 0.7, 0.9, 1.0, 1.6, 3.5, 4.0, 5.0, 7.0, 10.0
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 0.3 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 0.7 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 0.9, },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 1.0, },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 1.6 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 3.5 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 4.0 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 5.0 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 7.0 },
-        GaussianBlur::<KERNEL_SIDE>{ sigma: 10.0 },
+        Box::new(GaussianBlur::new(0.3)),
+        Box::new(GaussianBlur::new(0.7)),
+        Box::new(GaussianBlur::new(0.9)),
+        Box::new(GaussianBlur::new(1.0)),
+        Box::new(GaussianBlur::new(1.6)),
+        Box::new(GaussianBlur::new(3.5)),
+        Box::new(GaussianBlur::new(4.0)),
+        Box::new(GaussianBlur::new(5.0)),
+        Box::new(GaussianBlur::new(7.0)),
+        Box::new(GaussianBlur::new(10.0)),
     ];
 
     let num_kernels = kernels.len();
+    let max_side_len = kernels.iter().map(|kernel| kernel.radius() * 2 + 1).max().unwrap();
 
     let pipeline = make_pipeline(&forest, kernels.clone(), image.extent());
 
     let width = image.width();
     let height = image.height();
     let predictions: Vec<[f32; 4]> = timeit(
-        &format!("Convo a {width}x{height}x3c img with {num_kernels} kernel(s) of {KERNEL_SIDE}^2"),
-        || pipeline.process(&image).unwrap()
+        &format!("Convo a {width}x{height}x3c img with {num_kernels} kernel(s) of up to {max_side_len}^2"),
+        || pipeline.process(&image).unwrap().predictions.unwrap()
     );
 
     let num_pixels = (width * height) as usize;
@@ -160,7 +122,7 @@ fn main() {
     assert!(img_slice_f32.len() == num_pixels * 4);
 
     let rgba_u8: Vec<u8> = img_slice_f32.iter()
-        .map(|channel| (*channel * 255.0) as u8)
+        .map(|channel| (*channel * 255.0).round() as u8)
         .collect::<Vec<_>>();
     let parsed = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(width, height, rgba_u8)
         .expect("Could not parse rgba u8 image!!!");
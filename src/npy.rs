@@ -0,0 +1,357 @@
+//! Reading and writing of numpy's `.npy` format (versions 1.0, 2.0 and 3.0), so that training data,
+//! feature maps and predictions can be exchanged with numpy tooling.
+//!
+//! See <https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html>
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{self as ah, Context};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+/// numpy pads the header so that the data starts at a multiple of this
+const HEADER_ALIGNMENT: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Endianness{
+    Little,
+    Big,
+}
+
+impl Endianness{
+    pub const NATIVE: Self = if cfg!(target_endian = "big") { Self::Big } else { Self::Little };
+}
+
+/// Memory layout of the array data in the file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Order{
+    /// Row-major, last axis varies fastest
+    C,
+    /// Column-major, first axis varies fastest
+    Fortran,
+}
+
+/// Element types that can be stored in an `.npy` file
+pub trait NpyElement: Copy + Default{
+    /// The kind and size part of the numpy type descriptor, e.g. `f4`
+    const DESCR: &'static str;
+    const SIZE: usize;
+    fn from_bytes(bytes: &[u8], endianness: Endianness) -> Self;
+    fn extend_bytes(self, out: &mut Vec<u8>, endianness: Endianness);
+}
+
+macro_rules! impl_NpyElement { ($elem_type:ty, $descr:literal) => {
+    impl NpyElement for $elem_type {
+        const DESCR: &'static str = $descr;
+        const SIZE: usize = size_of::<$elem_type>();
+        fn from_bytes(bytes: &[u8], endianness: Endianness) -> Self {
+            let bytes = bytes.try_into().unwrap();
+            match endianness {
+                Endianness::Little => <$elem_type>::from_le_bytes(bytes),
+                Endianness::Big => <$elem_type>::from_be_bytes(bytes),
+            }
+        }
+        fn extend_bytes(self, out: &mut Vec<u8>, endianness: Endianness) {
+            match endianness {
+                Endianness::Little => out.extend(self.to_le_bytes()),
+                Endianness::Big => out.extend(self.to_be_bytes()),
+            }
+        }
+    }
+};}
+impl_NpyElement!(u8, "u1");
+impl_NpyElement!(u16, "u2");
+impl_NpyElement!(u32, "u4");
+impl_NpyElement!(i64, "i8");
+impl_NpyElement!(f32, "f4");
+impl_NpyElement!(f64, "f8");
+
+/// An n-dimensional array, kept in C order in memory regardless of the order it was stored in
+#[derive(Debug, Clone, PartialEq)]
+pub struct NpyArray<T>{
+    shape: Vec<usize>,
+    data: Vec<T>,
+}
+
+struct Header{
+    descr: String,
+    order: Order,
+    shape: Vec<usize>,
+}
+
+impl<T: NpyElement> NpyArray<T>{
+    pub fn new(shape: Vec<usize>, data: Vec<T>) -> ah::Result<Self>{
+        let num_elements: usize = shape.iter().product();
+        if num_elements != data.len(){
+            ah::bail!("Shape {shape:?} needs {num_elements} elements, got {}", data.len());
+        }
+        Ok(Self{shape, data})
+    }
+    pub fn shape(&self) -> &[usize]{
+        &self.shape
+    }
+    /// The elements in C order
+    pub fn data(&self) -> &[T]{
+        &self.data
+    }
+    pub fn into_data(self) -> Vec<T>{
+        self.data
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> ah::Result<Self>{
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).context(format!("Opening {}", path.display()))?;
+        Self::read_from(std::io::BufReader::new(file)).context(format!("Reading {}", path.display()))
+    }
+    pub fn save(&self, path: impl AsRef<Path>) -> ah::Result<()>{
+        let path = path.as_ref();
+        let file = std::fs::File::create(path).context(format!("Creating {}", path.display()))?;
+        let mut writer = std::io::BufWriter::new(file);
+        self.write_to(&mut writer, Endianness::Little, Order::C)?;
+        writer.flush().context(format!("Writing {}", path.display()))
+    }
+
+    pub fn read_from(mut reader: impl Read) -> ah::Result<Self>{
+        let mut preamble = [0u8; 8];
+        reader.read_exact(&mut preamble).context("Reading magic string")?;
+        if &preamble[..6] != MAGIC{
+            ah::bail!("Not an npy file");
+        }
+        let header_len = match preamble[6] {
+            1 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len).context("Reading header length")?;
+                usize::from(u16::from_le_bytes(len))
+            },
+            2 | 3 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len).context("Reading header length")?;
+                u32::from_le_bytes(len) as usize
+            },
+            version => ah::bail!("Unsupported npy version {version}.{}", preamble[7]),
+        };
+        let mut raw_header = vec![0u8; header_len];
+        reader.read_exact(&mut raw_header).context("Reading header")?;
+        // version 1 and 2 headers are latin1, but anything describing a plain numeric array is ascii
+        let header = Header::parse(std::str::from_utf8(&raw_header).context("Decoding header")?)?;
+
+        let (endianness, type_descr) = match header.descr.split_at_checked(1) {
+            Some(("<", rest)) => (Endianness::Little, rest),
+            Some((">", rest)) => (Endianness::Big, rest),
+            Some(("|" | "=", rest)) => (Endianness::NATIVE, rest),
+            _ => ah::bail!("Bad type descriptor '{}'", header.descr),
+        };
+        if type_descr != T::DESCR{
+            ah::bail!("Expected elements of type '{}', found '{}'", T::DESCR, header.descr);
+        }
+
+        let Some(data_len) = header.shape.iter().try_fold(T::SIZE, |len, axis_len| len.checked_mul(*axis_len)) else {
+            ah::bail!("Shape {:?} is too big", header.shape);
+        };
+        // the header could be corrupt, so the buffer only grows as far as the data actually goes
+        let mut raw_data = vec![];
+        reader.take(data_len as u64).read_to_end(&mut raw_data).context("Reading array data")?;
+        if raw_data.len() != data_len{
+            ah::bail!("Shape {:?} needs {data_len} bytes of data, but there are only {}", header.shape, raw_data.len());
+        }
+        let data: Vec<T> = raw_data.chunks_exact(T::SIZE).map(|bytes| T::from_bytes(bytes, endianness)).collect();
+
+        let data = match header.order {
+            Order::C => data,
+            Order::Fortran => reorder(&data, &header.shape, Order::Fortran, Order::C),
+        };
+        Ok(Self{shape: header.shape, data})
+    }
+
+    pub fn write_to(&self, mut writer: impl Write, endianness: Endianness, order: Order) -> ah::Result<()>{
+        let endianness_char = match (T::SIZE, endianness) {
+            (1, _) => '|',
+            (_, Endianness::Little) => '<',
+            (_, Endianness::Big) => '>',
+        };
+        let fortran_order = match order {
+            Order::C => "False",
+            Order::Fortran => "True",
+        };
+        let shape = match self.shape.as_slice() {
+            [len] => format!("({len},)"),
+            shape => format!("({})", shape.iter().map(|len| len.to_string()).collect::<Vec<_>>().join(", ")),
+        };
+        let mut header = format!(
+            "{{'descr': '{endianness_char}{}', 'fortran_order': {fortran_order}, 'shape': {shape}, }}", T::DESCR
+        );
+
+        // version 1.0 unless the padded header doesn't fit its 16 bit length field, like numpy does
+        let padded_len_with_field = |len_field_size: usize| {
+            (MAGIC.len() + 2 + len_field_size + header.len() + 1).next_multiple_of(HEADER_ALIGNMENT)
+        };
+        let (version, len_field_size) = if padded_len_with_field(2) - (MAGIC.len() + 2 + 2) <= u16::MAX as usize {
+            (1u8, 2)
+        } else {
+            (2u8, 4)
+        };
+        let preamble_len = MAGIC.len() + 2 + len_field_size;
+        let padded_len = padded_len_with_field(len_field_size);
+        header.extend(std::iter::repeat_n(' ', padded_len - preamble_len - header.len() - 1));
+        header.push('\n');
+
+        let mut bytes = Vec::with_capacity(padded_len + self.data.len() * T::SIZE);
+        bytes.extend(MAGIC);
+        bytes.extend([version, 0]);
+        match version {
+            1 => bytes.extend((header.len() as u16).to_le_bytes()),
+            _ => bytes.extend((header.len() as u32).to_le_bytes()),
+        }
+        bytes.extend(header.as_bytes());
+        let data = match order {
+            Order::C => self.data.clone(),
+            Order::Fortran => reorder(&self.data, &self.shape, Order::C, Order::Fortran),
+        };
+        for element in data{
+            element.extend_bytes(&mut bytes, endianness);
+        }
+        writer.write_all(&bytes).context("Writing npy data")
+    }
+}
+
+/// Rearranges `data` of the given `shape` from one memory order to the other
+fn reorder<T: Copy>(data: &[T], shape: &[usize], from: Order, to: Order) -> Vec<T>{
+    if from == to{
+        return data.to_vec()
+    }
+    let strides = |order: Order| -> Vec<usize> {
+        let mut strides = vec![0; shape.len()];
+        let mut stride = 1;
+        let axes: Box<dyn Iterator<Item = usize>> = match order {
+            Order::C => Box::new((0..shape.len()).rev()),
+            Order::Fortran => Box::new(0..shape.len()),
+        };
+        for axis in axes{
+            strides[axis] = stride;
+            stride *= shape[axis];
+        }
+        strides
+    };
+    let from_strides = strides(from);
+    let to_strides = strides(to);
+    let mut out = data.to_vec();
+    let mut index = vec![0usize; shape.len()];
+    for _ in 0..data.len(){
+        let from_offset: usize = index.iter().zip(&from_strides).map(|(i, s)| i * s).sum();
+        let to_offset: usize = index.iter().zip(&to_strides).map(|(i, s)| i * s).sum();
+        out[to_offset] = data[from_offset];
+        // advance the multi-dimensional index, last axis first
+        for axis in (0..shape.len()).rev(){
+            index[axis] += 1;
+            if index[axis] < shape[axis]{
+                break
+            }
+            index[axis] = 0;
+        }
+    }
+    out
+}
+
+impl Header{
+    fn parse(raw: &str) -> ah::Result<Self>{
+        let value_of = |key: &str| -> ah::Result<&str> {
+            let Some((_, after_key)) = raw.split_once(&format!("'{key}':")) else {
+                ah::bail!("Header has no '{key}' entry: {raw}");
+            };
+            Ok(after_key.trim_start())
+        };
+
+        let descr_raw = value_of("descr")?;
+        let Some(descr) = descr_raw.strip_prefix('\'').and_then(|d| d.split_once('\'')).map(|(d, _)| d) else {
+            ah::bail!("Could not parse descr in header: {raw}");
+        };
+
+        let order = match value_of("fortran_order")? {
+            s if s.starts_with("True") => Order::Fortran,
+            s if s.starts_with("False") => Order::C,
+            _ => ah::bail!("Could not parse fortran_order in header: {raw}"),
+        };
+
+        let shape_raw = value_of("shape")?;
+        let Some((shape_items, _)) = shape_raw.strip_prefix('(').and_then(|s| s.split_once(')')) else {
+            ah::bail!("Could not parse shape in header: {raw}");
+        };
+        let shape = shape_items.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse::<usize>().context(format!("Parsing shape in header: {raw}")))
+            .collect::<ah::Result<Vec<_>>>()?;
+
+        Ok(Self{descr: descr.to_owned(), order, shape})
+    }
+}
+
+#[test]
+fn test_npy_round_trip(){
+    fn round_trip<T: NpyElement + PartialEq + std::fmt::Debug>(values: Vec<T>){
+        let array = NpyArray::new(vec![2, 3, values.len() / 6], values).unwrap();
+        for endianness in [Endianness::Little, Endianness::Big]{
+            for order in [Order::C, Order::Fortran]{
+                let mut bytes = vec![];
+                array.write_to(&mut bytes, endianness, order).unwrap();
+                let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+                assert_eq!((10 + header_len) % HEADER_ALIGNMENT, 0);
+                assert_eq!(NpyArray::<T>::read_from(bytes.as_slice()).unwrap(), array);
+            }
+        }
+    }
+    round_trip((0..12u8).collect());
+    round_trip((0..12u16).map(|v| v * 1000).collect());
+    round_trip((0..12u32).map(|v| v * 100_000).collect());
+    round_trip((-6..6i64).map(|v| v * 1_000_000_000_000).collect());
+    round_trip((0..12).map(|v| v as f32 / 3.0).collect());
+    round_trip((0..12).map(|v| v as f64 / 7.0).collect());
+
+    // headers around the limit of version 1.0's 16 bit length, before and after padding
+    let mut versions = vec![];
+    for num_axes in 21_820..21_860{
+        let array = NpyArray::new(vec![1; num_axes], vec![7u8]).unwrap();
+        let mut bytes = vec![];
+        array.write_to(&mut bytes, Endianness::Little, Order::C).unwrap();
+        versions.push(bytes[6]);
+        assert_eq!(NpyArray::<u8>::read_from(bytes.as_slice()).unwrap(), array, "{num_axes} axes");
+    }
+    assert!(versions.contains(&1) && versions.contains(&2), "{versions:?}");
+}
+
+#[test]
+fn test_npy_reading(){
+    // a 2x3 array in fortran order, as written by numpy with a version 3.0 header
+    let mut bytes = vec![];
+    bytes.extend(MAGIC);
+    bytes.extend([3, 0]);
+    let header = "{'descr': '>u2', 'fortran_order': True, 'shape': (2, 3), }          \n";
+    bytes.extend((header.len() as u32).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    for value in [1u16, 4, 2, 5, 3, 6]{
+        bytes.extend(value.to_be_bytes());
+    }
+    let array = NpyArray::<u16>::read_from(bytes.as_slice()).unwrap();
+    assert_eq!(array.shape(), &[2, 3]);
+    assert_eq!(array.data(), &[1, 2, 3, 4, 5, 6]);
+
+    assert!(NpyArray::<f32>::read_from(bytes.as_slice()).is_err());
+    assert!(NpyArray::<u16>::read_from(&bytes[..bytes.len() - 1]).is_err());
+
+    // corrupt shapes must neither overflow nor allocate what the file doesn't hold
+    for shape in ["(18446744073709551615, 3)", "(1000000000000, )"]{
+        let mut corrupt = vec![];
+        corrupt.extend(MAGIC);
+        corrupt.extend([1, 0]);
+        let header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}\n");
+        corrupt.extend((header.len() as u16).to_le_bytes());
+        corrupt.extend(header.as_bytes());
+        corrupt.extend([0; 16]);
+        assert!(NpyArray::<f32>::read_from(corrupt.as_slice()).is_err(), "{shape}");
+    }
+
+    let features = NpyArray::<f32>::load("bench/features.npy").unwrap();
+    assert_eq!(features.shape(), &[438, 30]);
+    let labels = NpyArray::<u8>::load("bench/labels.npy").unwrap();
+    assert_eq!(labels.shape(), &[438]);
+}
@@ -0,0 +1,129 @@
+//! The colors that segmentations are drawn in, either by the shader or on the CPU from a [`LabelImage`].
+
+use crate::decision_tree::ClassInfo;
+use crate::feature_extractor_pipeline::pipeline::LabelImage;
+
+/// One RGBA color per class index. The alpha is how strongly the class covers the image in overlays
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette{
+    colors: Vec<[u8; 4]>,
+}
+
+impl Palette{
+    /// Class `i` gets `colors[i]`
+    pub fn new(colors: Vec<[u8; 4]>) -> Self{
+        Self{colors}
+    }
+    /// Opaque colors with hues spread evenly around the color wheel, so that neighboring class
+    /// indices are as far apart as `num_classes` allows
+    pub fn generated(num_classes: usize) -> Self{
+        let colors = (0..num_classes)
+            .map(|class_idx| {
+                let hue = class_idx as f32 / num_classes as f32;
+                let [r, g, b] = hsv_to_rgb(hue, 1.0, 1.0).map(|channel| (channel * 255.0).round() as u8);
+                [r, g, b, u8::MAX]
+            })
+            .collect();
+        Self{colors}
+    }
+    /// The colors from a forest's class table, with [`Self::generated`] colors for classes that don't have one
+    pub fn for_classes(classes: &[ClassInfo]) -> Self{
+        let generated = Self::generated(classes.len());
+        let colors = classes.iter()
+            .map(|class| class.color.unwrap_or(generated.colors[class.index]))
+            .collect();
+        Self{colors}
+    }
+    pub fn len(&self) -> usize{
+        self.colors.len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.colors.is_empty()
+    }
+    pub fn color(&self, class_idx: usize) -> [u8; 4]{
+        self.colors[class_idx]
+    }
+    /// [`Self::color`] with every channel scaled to `0.0..=1.0`, the way the shader outputs it
+    pub fn color_f32(&self, class_idx: usize) -> [f32; 4]{
+        self.colors[class_idx].map(|channel| f32::from(channel) / 255.0)
+    }
+    /// Draws each pixel of `labels` in the color of its class
+    pub fn render(&self, labels: &LabelImage, width: u32, height: u32) -> image::RgbaImage{
+        image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba(self.color(labels.get((y * width + x) as usize)))
+        })
+    }
+    /// Draws `labels` on top of `image`, letting it show through according to each class color's
+    /// alpha times `opacity`. The result is opaque
+    pub fn overlay(&self, labels: &LabelImage, image: &image::RgbaImage, opacity: f32) -> image::RgbaImage{
+        image::RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+            let [r, g, b, a] = self.color_f32(labels.get((y * image.width() + x) as usize));
+            let background = image.get_pixel(x, y).0;
+            let blend = a * opacity;
+            let mix = |background: u8, color: f32| ((f32::from(background) / 255.0 * (1.0 - blend) + color * blend) * 255.0).round() as u8;
+            image::Rgba([mix(background[0], r), mix(background[1], g), mix(background[2], b), u8::MAX])
+        })
+    }
+    /// Writes WGSL that declares `class_colors: array<vec4<f32>, N>` with the colors as in [`Self::color_f32`]
+    pub fn write_wgsl(&self, out: &mut impl std::fmt::Write) -> Result<(), std::fmt::Error>{
+        let colors: Vec<String> = (0..self.len())
+            .map(|class_idx| {
+                let [r, g, b, a] = self.color_f32(class_idx);
+                format!("vec4({r:?}, {g:?}, {b:?}, {a:?})")
+            })
+            .collect();
+        write!(out, "
+                var class_colors = array<vec4<f32>, {}>({});",
+            self.len(), colors.join(", ")
+        )
+    }
+}
+
+/// `hue`, `saturation` and `value` all in `0.0..=1.0`
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [f32; 3]{
+    let sector = (hue.fract() * 6.0).floor();
+    let offset = hue.fract() * 6.0 - sector;
+    let p = value * (1.0 - saturation);
+    let q = value * (1.0 - saturation * offset);
+    let t = value * (1.0 - saturation * (1.0 - offset));
+    match sector as u32 {
+        0 => [value, t, p],
+        1 => [q, value, p],
+        2 => [p, value, t],
+        3 => [p, q, value],
+        4 => [t, p, value],
+        _ => [value, p, q],
+    }
+}
+
+#[test]
+fn test_palettes(){
+    let generated = Palette::generated(3);
+    assert_eq!(generated.color(0), [255, 0, 0, 255]);
+    assert_eq!(generated.color(1), [0, 255, 0, 255]);
+    assert_eq!(generated.color(2), [0, 0, 255, 255]);
+    let many = Palette::generated(20);
+    for (a, b) in (0..20).zip(1..20){
+        assert_ne!(many.color(a), many.color(b));
+    }
+
+    let classes = vec![
+        ClassInfo{index: 0, name: "background".to_owned(), color: Some([0, 0, 0, 0])},
+        ClassInfo::unnamed(1),
+    ];
+    let palette = Palette::for_classes(&classes);
+    assert_eq!(palette.color(0), [0, 0, 0, 0]);
+    assert_eq!(palette.color(1), Palette::generated(2).color(1));
+
+    let labels = LabelImage::U8(vec![0, 1, 1, 0]);
+    let palette = Palette::new(vec![[255, 0, 0, 255], [0, 0, 255, 128]]);
+    let rendered = palette.render(&labels, 2, 2);
+    assert_eq!(rendered.get_pixel(1, 0).0, [0, 0, 255, 128]);
+    assert_eq!(rendered.get_pixel(1, 1).0, [255, 0, 0, 255]);
+
+    let image = image::RgbaImage::from_pixel(2, 2, image::Rgba([100, 100, 100, 255]));
+    let overlay = palette.overlay(&labels, &image, 1.0);
+    assert_eq!(overlay.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    assert_eq!(overlay.get_pixel(1, 0).0, [50, 50, 178, 255]);
+    assert_eq!(palette.overlay(&labels, &image, 0.0), image);
+}
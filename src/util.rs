@@ -3,9 +3,82 @@ use std::num::NonZeroU8;
 use std::fmt::Display;
 use std::time::{Duration, Instant};
 use colored::Colorize;
+use pollster::FutureExt;
 
 use crate::wgsl::ShaderTypeExt;
 
+/// Set to make GPU tests fail instead of being skipped when there is no adapter, e.g. in CI
+#[cfg(test)]
+pub const REQUIRE_GPU_VAR: &str = "GPU_FILTERS_REQUIRE_GPU";
+
+/// The device for a test that needs a GPU. Without an adapter, the test should return early, unless
+/// [`REQUIRE_GPU_VAR`] is set, in which case this panics so that the missing GPU can't go unnoticed
+#[cfg(test)]
+pub fn test_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    match request_device() {
+        Ok(device_and_queue) => Some(device_and_queue),
+        Err(err) if std::env::var_os(REQUIRE_GPU_VAR).is_some() => panic!("{REQUIRE_GPU_VAR} is set, but there is no GPU: {err}"),
+        Err(err) => {
+            eprintln!("SKIPPED: no GPU adapter available ({err}); set {REQUIRE_GPU_VAR}=1 to fail instead");
+            None
+        },
+    }
+}
+
+/// Creates a `Device` and `Queue` on a high-performance adapter that can run compute shaders
+pub fn request_device() -> Result<(wgpu::Device, wgpu::Queue), String> {
+    // We first initialize an wgpu `Instance`, which contains any "global" state wgpu needs.
+    //
+    // This is what loads the vulkan/dx12/metal/opengl libraries.
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor{
+        // flags: wgpu::InstanceFlags::debugging(),
+        ..Default::default()
+    });
+
+    // We then create an `Adapter` which represents a physical gpu in the system. It allows
+    // us to query information about it and create a `Device` from it.
+    //
+    // This function is asynchronous in WebGPU, so request_adapter returns a future. On native/webgl
+    // the future resolves immediately, so we can block on it without harm.
+    let adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions{
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            }
+        )
+        .block_on()
+        .map_err(|e| format!("Failed to create adapter: {e}"))?;
+
+    // Print out some basic information about the adapter.
+    println!("Running on Adapter: {:#?}", adapter.get_info());
+
+    // Check to see if the adapter supports compute shaders. While WebGPU guarantees support for
+    // compute shaders, wgpu supports a wider range of devices through the use of "downlevel" devices.
+    let downlevel_capabilities = adapter.get_downlevel_capabilities();
+    if !downlevel_capabilities
+        .flags
+        .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+    {
+        return Err("Adapter does not support compute shaders".into());
+    }
+
+    // We then create a `Device` and a `Queue` from the `Adapter`.
+    //
+    // The `Device` is used to create and manage GPU resources.
+    // The `Queue` is a queue used to submit work for the GPU to process.
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            required_limits: wgpu::Limits::downlevel_defaults(),
+            memory_hints: wgpu::MemoryHints::MemoryUsage,
+            trace: wgpu::Trace::Off,
+        },
+    )
+    .block_on()
+    .map_err(|e| format!("Failed to create device: {e}"))
+}
+
 pub struct WorkgroupSize {
     pub x: u32,
     pub y: u32,
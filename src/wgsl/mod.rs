@@ -8,6 +8,18 @@ pub mod expression;
 pub mod statement;
 pub mod texture;
 
+/// Parses and validates WGSL source with naga, like wgpu does when creating a shader module
+#[cfg(test)]
+pub fn validate_wgsl(code: &str) -> Result<(), String> {
+    use wgpu::naga;
+
+    let module = naga::front::wgsl::parse_str(code).map_err(|e| e.emit_to_string(code))?;
+    naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|e| e.emit_to_string(code))?;
+    Ok(())
+}
+
 pub trait Wgsl {
     fn wgsl(&self) -> String;
 }